@@ -1,9 +1,15 @@
 mod site;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use chrono::{FixedOffset, NaiveDateTime, TimeDelta};
 use clap::{Parser, Subcommand};
-use site::{MediaLibrary, Website};
+use site::server::ServeOptions;
+use site::{
+    BuildTarget, ImportOptions, MediaLibrary, MetaEdit, PreviewStore, PublishedMedia, Rebuild,
+    Selection, Website, import_media, parse_datetime_arg, parse_offset, parse_range_end,
+    parse_shift,
+};
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -29,12 +35,117 @@ enum Commands {
         /// Watch for file changes and rebuild
         #[arg(long)]
         watch: bool,
-        /// Port to listen on
+        /// Address to listen on, e.g. 0.0.0.0 to preview from other devices
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        /// Port to listen on, or the next free one if it's taken
         #[arg(short = 'p', long, default_value = "8088")]
         port: u16,
+        /// Include hidden, draft and scheduled items, marked with a badge. Implies
+        /// --preview, so they never end up in the build directory
+        #[arg(long)]
+        drafts: bool,
+        /// Serve the pages from memory, leaving the build directory untouched
+        #[arg(long)]
+        preview: bool,
     },
     /// Update media metadata in the current directory
     Update,
+    /// Delete the build directory and caches of the site in the current directory
+    Clean,
+    /// Copy or move media files from outside the site into media/
+    Add {
+        /// Files or directories to import
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Album (folder inside media/) to import into
+        #[arg(long)]
+        into: Option<String>,
+        /// Move the files instead of copying them
+        #[arg(long = "move")]
+        move_files: bool,
+        /// Import from subdirectories too
+        #[arg(short, long)]
+        recursive: bool,
+        /// Create a sidecar .txt stub for every imported file
+        #[arg(long)]
+        sidecar: bool,
+        /// Only import files dated on or after this date
+        #[arg(long, value_parser = parse_range_start)]
+        from: Option<NaiveDateTime>,
+        /// Only import files dated on or before this date (a bare date includes the whole day)
+        #[arg(long, value_parser = parse_range_end)]
+        to: Option<NaiveDateTime>,
+    },
+    /// Set or shift item dates and override titles, descriptions and flags
+    #[command(alias = "set-date")]
+    Edit {
+        /// Items to edit: paths relative to media/ or globs like 'robot-arm/*.jpg'
+        items: Vec<String>,
+        /// Only edit items dated on or after this date
+        #[arg(long, value_parser = parse_range_start)]
+        from: Option<NaiveDateTime>,
+        /// Only edit items dated on or before this date (a bare date includes the whole day)
+        #[arg(long, value_parser = parse_range_end)]
+        to: Option<NaiveDateTime>,
+        /// Set the date, e.g. 2024-03-01T23:30:00 or 2024-03-01T23:30:00+02:00
+        #[arg(long, value_parser = parse_datetime_arg, conflicts_with = "shift")]
+        date: Option<(NaiveDateTime, Option<FixedOffset>)>,
+        /// Shift the date by a duration, e.g. +2h, -1d or -30m
+        #[arg(long, value_parser = parse_shift, allow_hyphen_values = true)]
+        shift: Option<TimeDelta>,
+        /// Set the UTC offset of the date, e.g. +02:00
+        #[arg(long, value_parser = parse_timezone, allow_hyphen_values = true)]
+        timezone: Option<FixedOffset>,
+        /// Override the title (an empty string removes the override)
+        #[arg(long)]
+        title: Option<String>,
+        /// Override the description (an empty string removes the override)
+        #[arg(long)]
+        description: Option<String>,
+        /// Pin the entries so `update` never touches them
+        #[arg(long, conflicts_with = "unpin")]
+        pin: bool,
+        /// Unpin the entries
+        #[arg(long)]
+        unpin: bool,
+        /// Add a tag (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Remove a tag set in metamedia.toml (repeatable, sidecar tags stay)
+        #[arg(long = "untag")]
+        untags: Vec<String>,
+        /// Never publish the items
+        #[arg(long, conflicts_with = "unhide")]
+        hidden: bool,
+        /// Publish the items again
+        #[arg(long)]
+        unhide: bool,
+        /// Mark the items as work in progress
+        #[arg(long, conflicts_with = "undraft")]
+        draft: bool,
+        /// Remove the draft mark
+        #[arg(long)]
+        undraft: bool,
+        /// Publish the items from this date on (an empty string removes the schedule)
+        #[arg(long, value_parser = parse_publish_after)]
+        publish_after: Option<String>,
+    },
+}
+
+fn parse_range_start(text: &str) -> Result<NaiveDateTime, String> {
+    parse_datetime_arg(text).map(|(datetime, _)| datetime)
+}
+
+fn parse_publish_after(text: &str) -> Result<String, String> {
+    if !text.is_empty() {
+        parse_datetime_arg(text)?;
+    }
+    Ok(text.to_string())
+}
+
+fn parse_timezone(text: &str) -> Result<FixedOffset, String> {
+    parse_offset(text).ok_or_else(|| format!("invalid UTC offset '{}'", text))
 }
 
 fn main() {
@@ -60,7 +171,7 @@ fn main() {
         Commands::Build => {
             let path = Path::new(".");
             match Website::load(path) {
-                Ok(website) => match website.build(None) {
+                Ok(website) => match website.build(None, false) {
                     Ok(report) => {
                         println!("Site '{}' built successfully\n", website.info.title);
                         println!("{}", report);
@@ -76,11 +187,46 @@ fn main() {
                 }
             }
         }
-        Commands::Server { url, watch, port } => {
+        Commands::Server {
+            url,
+            watch,
+            host,
+            port,
+            drafts,
+            preview,
+        } => {
             let path = Path::new(".");
             match Website::load(path) {
                 Ok(website) => {
-                    match website.build(url.as_deref()) {
+                    let website = website.served();
+                    // Bound before building, the port may not be the one asked for
+                    let listener = match site::server::bind(&host, port) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            eprintln!("Server error: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let url = match listener.local_addr() {
+                        Ok(addr) => {
+                            url.map(|url| site::server::follow_port(&url, port, addr.port()))
+                        }
+                        Err(_) => url,
+                    };
+                    let target = if preview || drafts {
+                        BuildTarget::Preview(PreviewStore::default())
+                    } else {
+                        BuildTarget::Deploy
+                    };
+                    let mut published = PublishedMedia::default();
+                    let built = website.rebuild(
+                        url.as_deref(),
+                        drafts,
+                        &target,
+                        &Rebuild::Full,
+                        &mut published,
+                    );
+                    match built {
                         Ok(report) => {
                             println!(
                                 "Site '{}' built successfully\n{}",
@@ -93,10 +239,18 @@ fn main() {
                         }
                     }
 
-                    let build_dir = website.path.join("build");
-                    if let Err(e) =
-                        site::server::serve(build_dir, port, watch, website.path.clone(), url)
-                    {
+                    let options = ServeOptions {
+                        watch,
+                        base_url_override: url,
+                        drafts,
+                    };
+                    if let Err(e) = site::server::serve(
+                        listener,
+                        website.path.clone(),
+                        target,
+                        published,
+                        options,
+                    ) {
                         eprintln!("Server error: {}", e);
                         std::process::exit(1);
                     }
@@ -120,7 +274,7 @@ fn main() {
                     };
 
                     let media_path = website.path.join("media");
-                    match library.update_metadata(&media_path) {
+                    match library.update_metadata(&media_path, website.info.timezone) {
                         Ok(report) => {
                             println!("Updated metadata: {}", report);
                         }
@@ -136,5 +290,163 @@ fn main() {
                 }
             }
         }
+        Commands::Clean => {
+            let path = Path::new(".");
+            match Website::load(path) {
+                Ok(website) => match website.clean() {
+                    Ok(true) => println!("Removed the build directory and cache"),
+                    Ok(false) => println!("Nothing to clean"),
+                    Err(e) => {
+                        eprintln!("Error cleaning site: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Add {
+            paths,
+            into,
+            move_files,
+            recursive,
+            sidecar,
+            from,
+            to,
+        } => {
+            let path = Path::new(".");
+            let website = match Website::load(path) {
+                Ok(website) => website,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut library = match MediaLibrary::new(&website.path) {
+                Ok(lib) => lib,
+                Err(e) => {
+                    eprintln!("Error loading media library: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let media_path = website.path.join("media");
+            if let Err(e) = library.update_metadata(&media_path, website.info.timezone) {
+                eprintln!("Error updating metadata: {}", e);
+                std::process::exit(1);
+            }
+
+            let options = ImportOptions {
+                album: into,
+                move_files,
+                recursive,
+                sidecar_stubs: sidecar,
+                from,
+                to,
+            };
+            match import_media(
+                &mut library,
+                &media_path,
+                &paths,
+                &options,
+                website.info.timezone,
+            ) {
+                Ok(report) => {
+                    for (source, name) in &report.imported {
+                        println!("  {} -> media/{}", source.display(), name);
+                    }
+                    println!("Imported media: {}", report);
+                }
+                Err(e) => {
+                    eprintln!("Error importing media: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Edit {
+            items,
+            from,
+            to,
+            date,
+            shift,
+            timezone,
+            title,
+            description,
+            pin,
+            unpin,
+            tags,
+            untags,
+            hidden,
+            unhide,
+            draft,
+            undraft,
+            publish_after,
+        } => {
+            let path = Path::new(".");
+            let website = match Website::load(path) {
+                Ok(website) => website,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            // Sync first, so newly added files can be edited right away
+            let mut library = match MediaLibrary::new(&website.path) {
+                Ok(lib) => lib,
+                Err(e) => {
+                    eprintln!("Error loading media library: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let media_path = website.path.join("media");
+            if let Err(e) = library.update_metadata(&media_path, website.info.timezone) {
+                eprintln!("Error updating metadata: {}", e);
+                std::process::exit(1);
+            }
+
+            let selection = Selection {
+                patterns: items,
+                from,
+                to,
+            };
+            let edit = MetaEdit {
+                datetime: date.map(|(datetime, _)| datetime),
+                shift,
+                timezone: timezone.or(date.and_then(|(_, offset)| offset)),
+                title,
+                description,
+                pinned: switch(pin, unpin),
+                add_tags: tags,
+                remove_tags: untags,
+                hidden: switch(hidden, unhide),
+                draft: switch(draft, undraft),
+                publish_after,
+            };
+
+            match library.edit(&selection, &edit) {
+                Ok(edited) => {
+                    for name in &edited {
+                        println!("  {}", name);
+                    }
+                    println!("Edited {} item(s)", edited.len());
+                }
+                Err(e) => {
+                    eprintln!("Error editing metadata: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// The value of a pair of `--flag`/`--unflag` switches, if either was given.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
     }
 }
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::url_path::encode_path;
+
+/// A subdirectory of `media/`, rendered as its own gallery page.
+#[derive(Serialize, Clone)]
+pub struct Album {
+    /// Path relative to the media directory, `/`-separated.
+    pub path: String,
+    pub url: String,
+    /// Thumbnail of the most recent item in the album.
+    pub cover_url: String,
+    pub count: usize,
+}
+
+impl Album {
+    /// Returns `true` if this album lives (directly or deeper) inside `parent`.
+    pub fn is_nested_in(&self, parent: &str) -> bool {
+        self.path
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Relative prefix from the album page back to the site root.
+    pub fn root_prefix(&self) -> String {
+        "../".repeat(self.path.split('/').count() + 1)
+    }
+}
+
+/// Groups items by album. Takes `(album, timestamp, thumb_url)` for every item and
+/// returns the albums sorted by path.
+pub fn collect_albums<'a>(
+    items: impl Iterator<Item = (&'a str, i64, &'a str)>,
+    base_url: &str,
+    albums_dir: &str,
+) -> Vec<Album> {
+    let mut grouped: BTreeMap<&str, (usize, i64, &str)> = BTreeMap::new();
+
+    for (album, timestamp, thumb_url) in items {
+        let entry = grouped.entry(album).or_insert((0, timestamp, thumb_url));
+        entry.0 += 1;
+        if timestamp > entry.1 {
+            entry.1 = timestamp;
+            entry.2 = thumb_url;
+        }
+    }
+
+    grouped
+        .into_iter()
+        .map(|(path, (count, _, cover_url))| Album {
+            path: path.to_string(),
+            url: format!("{}/{}/{}/", base_url, albums_dir, encode_path(path)),
+            cover_url: cover_url.to_string(),
+            count,
+        })
+        .collect()
+}
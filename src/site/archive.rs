@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use chrono::Month;
+use serde::Serialize;
+
+/// A year or a month of the timeline, rendered as its own gallery page.
+#[derive(Serialize, Clone)]
+pub struct Period {
+    /// `2024` or `2024-03`, the prefix of the `datetime` of the items it holds.
+    pub key: String,
+    /// `2024` or `March 2024`.
+    pub title: String,
+    /// `2024` or `Mar`, for listings already grouped by year.
+    pub label: String,
+    pub url: String,
+    pub count: usize,
+    /// Directory inside the build, ending in `/`.
+    #[serde(skip)]
+    pub dir: String,
+    /// A year's months, newest first. Empty for months.
+    pub months: Vec<Period>,
+}
+
+impl Period {
+    /// Returns `true` if an item captured at `datetime` (local time, ISO 8601)
+    /// falls within this period.
+    pub fn contains(&self, datetime: &str) -> bool {
+        datetime.starts_with(&self.key)
+    }
+
+    /// Relative prefix from the period's page back to the site root.
+    pub fn root_prefix(&self) -> String {
+        "../".repeat(self.dir.matches('/').count())
+    }
+}
+
+/// A month heading in a gallery's JSON chunk, telling the grid where to draw a
+/// date separator.
+#[derive(Serialize)]
+pub struct Section<'a> {
+    pub key: &'a str,
+    pub title: &'a str,
+    pub url: &'a str,
+    pub count: usize,
+}
+
+/// Groups item datetimes into years and months, newest first.
+pub fn collect_archive<'a>(
+    datetimes: impl Iterator<Item = &'a str>,
+    base_url: &str,
+    archive_dir: &str,
+) -> Vec<Period> {
+    let mut grouped: BTreeMap<i32, BTreeMap<u32, usize>> = BTreeMap::new();
+
+    for datetime in datetimes {
+        let year = datetime.get(..4).and_then(|y| y.parse::<i32>().ok());
+        let month = datetime.get(5..7).and_then(|m| m.parse::<u32>().ok());
+        if let (Some(year), Some(month)) = (year, month) {
+            *grouped.entry(year).or_default().entry(month).or_insert(0) += 1;
+        }
+    }
+
+    grouped
+        .into_iter()
+        .rev()
+        .map(|(year, months)| {
+            let dir = format!("{}/{}/", archive_dir, year);
+            let months: Vec<Period> = months
+                .into_iter()
+                .rev()
+                .map(|(month, count)| {
+                    let name = u8::try_from(month)
+                        .ok()
+                        .and_then(|m| Month::try_from(m).ok())
+                        .map_or("", |m| m.name());
+                    let dir = format!("{}{:02}/", dir, month);
+                    Period {
+                        key: format!("{}-{:02}", year, month),
+                        title: format!("{} {}", name, year),
+                        label: name.get(..3).unwrap_or(name).to_string(),
+                        url: format!("{}/{}", base_url, dir),
+                        count,
+                        dir,
+                        months: Vec::new(),
+                    }
+                })
+                .collect();
+            Period {
+                key: year.to_string(),
+                title: year.to_string(),
+                label: year.to_string(),
+                url: format!("{}/{}", base_url, dir),
+                count: months.iter().map(|m| m.count).sum(),
+                dir,
+                months,
+            }
+        })
+        .collect()
+}
+
+/// The month sections covering `datetimes`, in order of first appearance.
+pub fn sections<'a>(
+    archive: &'a [Period],
+    datetimes: impl Iterator<Item = &'a str>,
+) -> Vec<Section<'a>> {
+    let mut sections: Vec<Section> = Vec::new();
+    for datetime in datetimes {
+        if sections.last().is_some_and(|s| datetime.starts_with(s.key)) {
+            continue;
+        }
+        let month = archive
+            .iter()
+            .flat_map(|year| &year.months)
+            .find(|month| month.contains(datetime));
+        if let Some(month) = month
+            && !sections.iter().any(|s| s.key == month.key)
+        {
+            sections.push(Section {
+                key: &month.key,
+                title: &month.title,
+                url: &month.url,
+                count: month.count,
+            });
+        }
+    }
+    sections
+}
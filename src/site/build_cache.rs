@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::media_library::{CLUTTERLOG_DIR, hash_file};
+use super::website::WebsiteError;
+
+const CACHE_DIR: &str = "cache";
+const SOURCES_TOML: &str = "sources.toml";
+/// Width of a source that had to be decoded to be measured, next to its derivatives.
+const MEASURED_WIDTH_FILE: &str = "width.txt";
+
+/// Distinguishes the temporary files of derivatives being generated concurrently.
+static PARTIAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Generated derivatives (display copies, thumbnails, transcodes), kept in
+/// `.clutterlog/cache/<source hash>/` under a key derived from the source's
+/// contents and the parameters the derivative was made with. Unlike output mtimes, the keys
+/// survive a fresh checkout, so CI can restore the directory and skip the work,
+/// and a settings change only misses the derivatives it affects.
+pub struct BuildCache {
+    dir: PathBuf,
+    /// Source hashes from the previous build, by name relative to `media/`.
+    sources: HashMap<String, SourceHash>,
+}
+
+/// A source's content hash, along with the size and mtime it was computed for.
+/// Hashing is skipped while those stay the same.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SourceHash {
+    size: u64,
+    modified: i64,
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SourcesFile {
+    #[serde(default)]
+    sources: BTreeMap<String, SourceHash>,
+}
+
+impl BuildCache {
+    pub fn load(site_path: &Path) -> Self {
+        let dir = cache_dir(site_path);
+        let sources = fs::read_to_string(dir.join(SOURCES_TOML))
+            .ok()
+            .and_then(|content| toml::from_str::<SourcesFile>(&content).ok())
+            .map(|file| file.sources.into_iter().collect())
+            .unwrap_or_default();
+        Self { dir, sources }
+    }
+
+    /// Returns the content hash of the source `name`, reusing the previous build's
+    /// hash if the file's size and mtime haven't changed.
+    pub fn hash_source(&self, name: &str, path: &Path) -> io::Result<SourceHash> {
+        let metadata = fs::metadata(path)?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_nanos() as i64);
+
+        if let Some(known) = self.sources.get(name)
+            && known.size == size
+            && known.modified == modified
+        {
+            return Ok(known.clone());
+        }
+
+        Ok(SourceHash {
+            size,
+            modified,
+            hash: hash_file(path)?,
+        })
+    }
+
+    /// Where the derivative with `key` of the source with `source_hash` is (or
+    /// will be) stored.
+    pub fn path(&self, source_hash: &str, key: &str, extension: &str) -> PathBuf {
+        self.dir
+            .join(source_hash)
+            .join(format!("{}.{}", key, extension))
+    }
+
+    /// Records this build's source hashes and evicts derivatives. Sources gone
+    /// from the library (none of `library` is their hash) lose all of them.
+    /// Sources this build published lose the ones it didn't use, which the
+    /// current settings no longer produce. The rest, say drafts only a
+    /// `server --drafts` preview published, keep theirs for the next time. A
+    /// measured width stays as long as its source.
+    pub fn save(
+        &self,
+        sources: BTreeMap<String, SourceHash>,
+        used: &HashSet<PathBuf>,
+        library: &HashSet<&str>,
+    ) -> Result<(), WebsiteError> {
+        fs::create_dir_all(&self.dir).map_err(|e| WebsiteError::Io(self.dir.clone(), e))?;
+
+        let published: HashSet<&str> = sources.values().map(|s| s.hash.as_str()).collect();
+        for entry in fs::read_dir(&self.dir).map_err(|e| WebsiteError::Io(self.dir.clone(), e))? {
+            let dir = entry
+                .map_err(|e| WebsiteError::Io(self.dir.clone(), e))?
+                .path();
+            if !dir.is_dir() {
+                continue;
+            }
+            let hash = dir.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if !library.contains(hash) {
+                fs::remove_dir_all(&dir).map_err(|e| WebsiteError::Io(dir.clone(), e))?;
+                continue;
+            }
+            if !published.contains(hash) {
+                continue;
+            }
+            let files = fs::read_dir(&dir).map_err(|e| WebsiteError::Io(dir.clone(), e))?;
+            for file in files.filter_map(|entry| entry.ok().map(|e| e.path())) {
+                let measured = file.file_name().is_some_and(|n| n == MEASURED_WIDTH_FILE);
+                if !used.contains(&file) && !measured {
+                    fs::remove_file(&file).map_err(|e| WebsiteError::Io(file.clone(), e))?;
+                }
+            }
+            // Only succeeds once the directory is empty
+            let _ = fs::remove_dir(&dir);
+        }
+
+        self.record_sources(sources)
+            .map_err(|e| WebsiteError::Io(self.dir.join(SOURCES_TOML), e))
+    }
+
+    /// The width of the source with `source_hash`, from an earlier build or else
+    /// from `measure`, which is remembered when it succeeds.
+    pub fn measured_width(
+        &self,
+        source_hash: &str,
+        measure: impl FnOnce() -> Option<u32>,
+    ) -> Option<u32> {
+        let path = self.dir.join(source_hash).join(MEASURED_WIDTH_FILE);
+        if let Some(width) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+        {
+            return Some(width);
+        }
+
+        let width = measure()?;
+        // Only a speedup for the next build, a width that can't be written is
+        // measured again
+        let _ = fs::create_dir_all(self.dir.join(source_hash))
+            .and_then(|_| fs::write(&path, width.to_string()));
+        Some(width)
+    }
+
+    /// Records source hashes for the next run to reuse, without touching the
+    /// derivatives.
+    pub fn record_sources(&self, sources: BTreeMap<String, SourceHash>) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let content = toml::to_string_pretty(&SourcesFile { sources }).map_err(io::Error::other)?;
+        fs::write(self.dir.join(SOURCES_TOML), content)
+    }
+}
+
+pub fn cache_dir(site_path: &Path) -> PathBuf {
+    site_path.join(CLUTTERLOG_DIR).join(CACHE_DIR)
+}
+
+/// Cache key for a derivative of the source with `source_hash`, made with `params`.
+pub fn derivative_key(source_hash: &str, params: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(source_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(params.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// A temporary path next to `path` to generate into, so an interrupted build never
+/// leaves a truncated file under the final name. Keeps the extension, which
+/// ffmpeg picks its output format from.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let n = PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    path.with_file_name(format!("{}.{}.partial.{}", stem, n, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn source(hash: &str) -> SourceHash {
+        SourceHash {
+            size: 1,
+            modified: 0,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn evicts_only_what_no_build_needs() {
+        let site = tempfile::tempdir().unwrap();
+        let cache = BuildCache::load(site.path());
+        let derivative = |hash: &str, key: &str| {
+            let path = cache.path(hash, key, "webp");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, key).unwrap();
+            path
+        };
+        let thumb = derivative("published", "thumb");
+        let old_thumb = derivative("published", "old-thumb");
+        let draft = derivative("draft", "thumb");
+        let gone = derivative("gone", "thumb");
+        let unused = derivative("replaced", "thumb");
+
+        // A deploy build after a `server --drafts` preview: the draft is still in
+        // the library, just not published this time
+        let sources = BTreeMap::from([
+            ("a.png".to_string(), source("published")),
+            ("b.png".to_string(), source("replaced")),
+        ]);
+        let used = HashSet::from([thumb.clone()]);
+        let library = HashSet::from(["published", "replaced", "draft"]);
+        cache.save(sources, &used, &library).unwrap();
+
+        assert!(thumb.is_file());
+        assert!(!old_thumb.exists());
+        assert!(draft.is_file());
+        assert!(!gone.parent().unwrap().exists());
+        assert!(!unused.parent().unwrap().exists());
+
+        let reloaded = BuildCache::load(site.path());
+        assert_eq!(reloaded.sources["a.png"].hash, "published");
+        assert_eq!(reloaded.sources.len(), 2);
+    }
+
+    #[test]
+    fn measures_each_source_once() {
+        let site = tempfile::tempdir().unwrap();
+        let cache = BuildCache::load(site.path());
+        let runs = Cell::new(0);
+        let measure = || {
+            runs.set(runs.get() + 1);
+            Some(4032)
+        };
+
+        assert_eq!(cache.measured_width("abc", measure), Some(4032));
+        assert_eq!(cache.measured_width("abc", measure), Some(4032));
+        assert_eq!(runs.get(), 1);
+
+        // Failures aren't remembered, ffmpeg may be installed by the next build
+        assert_eq!(cache.measured_width("def", || None), None);
+        assert_eq!(cache.measured_width("def", measure), Some(4032));
+        assert_eq!(runs.get(), 2);
+
+        // Publishing the source again keeps the width, even though no derivative
+        // uses it
+        let sources = BTreeMap::from([("a.heic".to_string(), source("abc"))]);
+        cache
+            .save(sources, &HashSet::new(), &HashSet::from(["abc"]))
+            .unwrap();
+        assert_eq!(cache.measured_width("abc", measure), Some(4032));
+        assert_eq!(runs.get(), 2);
+    }
+}
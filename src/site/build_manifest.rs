@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::media_library::CLUTTERLOG_DIR;
+use super::preview::PreviewStore;
+use super::website::WebsiteError;
+
+/// Kept in `.clutterlog/` rather than in the build directory, which gets deployed.
+const DEPLOY_MANIFEST: &str = "build-manifest";
+const PREVIEW_MANIFEST: &str = "preview-manifest";
+
+/// Where earlier versions kept the manifest, inside the build directory.
+const LEGACY_MANIFEST: &str = ".clutterlog-manifest";
+
+/// Every file a build wrote into the build directory, as `/`-separated paths
+/// relative to it. Comparing it with the previous build's manifest tells which
+/// outputs went stale. Media files also record the cache key of the derivative
+/// they were published from, so unchanged ones aren't copied again.
+#[derive(Default)]
+pub struct BuildManifest {
+    files: BTreeMap<String, String>,
+    /// Files this build wrote with new contents or removed. Not saved.
+    changed: Vec<String>,
+    /// Set for preview builds, which keep what they render here instead of
+    /// writing it out. Media is published to the build directory all the same.
+    store: Option<PreviewStore>,
+}
+
+impl BuildManifest {
+    /// Reads the manifest at `path` left by the previous build. A missing or
+    /// unreadable one is treated as empty.
+    pub fn load(path: &Path) -> Self {
+        let files = fs::read_to_string(path)
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| line.split_once(' '))
+                    .map(|(key, file)| {
+                        let key = if key == "-" { "" } else { key };
+                        (file.to_string(), key.to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            files,
+            changed: Vec::new(),
+            store: None,
+        }
+    }
+
+    /// An empty manifest for a new build, rendering into `store` if given.
+    pub fn new(store: Option<PreviewStore>) -> Self {
+        Self {
+            store,
+            ..Self::default()
+        }
+    }
+
+    /// Writes the manifest to `path`, and deletes the one an earlier version left
+    /// in `build_path`, so it stops being deployed.
+    pub fn save(&self, path: &Path, build_path: &Path) -> Result<(), WebsiteError> {
+        let mut content = String::new();
+        for (file, key) in &self.files {
+            let key = if key.is_empty() { "-" } else { key };
+            content.push_str(&format!("{} {}\n", key, file));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| WebsiteError::Io(parent.to_path_buf(), e))?;
+        }
+        fs::write(path, content).map_err(|e| WebsiteError::Io(path.to_path_buf(), e))?;
+
+        let legacy = build_path.join(LEGACY_MANIFEST);
+        match fs::remove_file(&legacy) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(WebsiteError::Io(legacy, e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a file published from the cached derivative `key`. It counts as
+    /// changed unless the `previous` build published it from the same key.
+    pub fn insert(&mut self, relative: String, key: String, previous: &BuildManifest) {
+        if previous.key(&relative) != Some(key.as_str()) {
+            self.changed.push(relative.clone());
+        }
+        self.files.insert(relative, key);
+    }
+
+    /// Files written with new contents or removed so far.
+    pub fn changed(&self) -> &[String] {
+        &self.changed
+    }
+
+    /// The cache key `relative` was published from, if it came from the cache.
+    pub fn key(&self, relative: &str) -> Option<&str> {
+        self.files
+            .get(relative)
+            .map(String::as_str)
+            .filter(|key| !key.is_empty())
+    }
+
+    /// Writes `content` to `relative` inside `build_path`, or into the preview
+    /// store, and records it. A file that already holds `content` is left
+    /// untouched, so its mtime only moves when it actually changes.
+    pub fn write(
+        &mut self,
+        build_path: &Path,
+        relative: &str,
+        content: &str,
+    ) -> Result<(), WebsiteError> {
+        let path = build_path.join(relative);
+        if let Some(store) = &self.store {
+            if store.insert(relative, content) {
+                self.changed.push(relative.to_string());
+            }
+        } else if fs::read(&path).ok().as_deref() != Some(content.as_bytes()) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| WebsiteError::Io(parent.to_path_buf(), e))?;
+            }
+            fs::write(&path, content).map_err(|e| WebsiteError::Io(path.clone(), e))?;
+            self.changed.push(relative.to_string());
+        }
+        self.files.insert(relative.to_string(), String::new());
+        Ok(())
+    }
+
+    /// Deletes the outputs this build didn't produce: anything inside `media_dir`,
+    /// which only ever holds generated files, and anything else the `previous`
+    /// build wrote. Files put into the build directory by hand (a `CNAME`, say)
+    /// are left alone. Returns the number of files removed.
+    pub fn prune(
+        &mut self,
+        build_path: &Path,
+        previous: &BuildManifest,
+        media_dir: &str,
+    ) -> Result<usize, WebsiteError> {
+        let mut stale: BTreeSet<String> = previous
+            .files
+            .keys()
+            .filter(|file| !self.files.contains_key(*file))
+            .cloned()
+            .collect();
+
+        let mut media_files = Vec::new();
+        collect_files(&build_path.join(media_dir), media_dir, &mut media_files)
+            .map_err(|e| WebsiteError::Io(build_path.join(media_dir), e))?;
+        stale.extend(
+            media_files
+                .into_iter()
+                .filter(|file| !self.files.contains_key(file)),
+        );
+
+        let mut removed = 0;
+        if let Some(store) = &self.store {
+            for file in store.retain(|file| self.files.contains_key(file)) {
+                removed += 1;
+                self.changed.push(file);
+            }
+        }
+        for file in &stale {
+            let path = build_path.join(file);
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    removed += 1;
+                    self.changed.push(file.clone());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(WebsiteError::Io(path, e)),
+            }
+            remove_empty_parents(build_path, &path);
+        }
+        Ok(removed)
+    }
+}
+
+/// Where the manifest of the builds into `build/`, or of the preview builds, is kept.
+pub fn manifest_path(site_path: &Path, preview: bool) -> PathBuf {
+    let name = if preview {
+        PREVIEW_MANIFEST
+    } else {
+        DEPLOY_MANIFEST
+    };
+    site_path.join(CLUTTERLOG_DIR).join(name)
+}
+
+/// Lists the files under `dir` as `/`-separated paths starting with `prefix`.
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let relative = format!("{}/{}", prefix, name);
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &relative, files)?;
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the directories left empty by deleting `path`, stopping at `root`.
+fn remove_empty_parents(root: &Path, path: &Path) {
+    let mut dir: Option<PathBuf> = path.parent().map(Path::to_path_buf);
+    while let Some(current) = dir {
+        if current == root || fs::remove_dir(&current).is_err() {
+            break;
+        }
+        dir = current.parent().map(Path::to_path_buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(build: &Path, relative: &str) {
+        let path = build.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, relative).unwrap();
+    }
+
+    #[test]
+    fn round_trips_names_with_spaces() {
+        let site = tempfile::tempdir().unwrap();
+        let build = site.path().join("build");
+        let path = manifest_path(site.path(), false);
+
+        let mut manifest = BuildManifest::new(None);
+        manifest
+            .write(&build, "m/first print/index.html", "page")
+            .unwrap();
+        let previous = BuildManifest::default();
+        manifest.insert(
+            "media/robot arm/first print.png".to_string(),
+            "abc".to_string(),
+            &previous,
+        );
+        manifest.save(&path, &build).unwrap();
+
+        let loaded = BuildManifest::load(&path);
+        assert_eq!(loaded.key("media/robot arm/first print.png"), Some("abc"));
+        assert_eq!(loaded.key("m/first print/index.html"), None);
+        assert!(loaded.files.contains_key("m/first print/index.html"));
+        assert_eq!(loaded.files.len(), 2);
+    }
+
+    #[test]
+    fn prunes_only_stale_outputs() {
+        let site = tempfile::tempdir().unwrap();
+        let build = site.path().join("build");
+        let path = manifest_path(site.path(), false);
+
+        let mut first = BuildManifest::new(None);
+        first.write(&build, "index.html", "home").unwrap();
+        first.write(&build, "m/a/index.html", "a").unwrap();
+        let empty = BuildManifest::default();
+        for (file, key) in [("media/a.png", "ka"), ("media/album/b.png", "kb")] {
+            write(&build, file);
+            first.insert(file.to_string(), key.to_string(), &empty);
+        }
+        first.save(&path, &build).unwrap();
+        // Put there by hand, and left behind by an interrupted build
+        write(&build, "CNAME");
+        write(&build, "media/leftover.png");
+
+        let previous = BuildManifest::load(&path);
+        let mut second = BuildManifest::new(None);
+        second.write(&build, "index.html", "home").unwrap();
+        second.insert("media/a.png".to_string(), "ka".to_string(), &previous);
+        let removed = second.prune(&build, &previous, "media").unwrap();
+
+        assert_eq!(removed, 3);
+        assert!(build.join("CNAME").is_file());
+        assert!(build.join("index.html").is_file());
+        assert!(build.join("media/a.png").is_file());
+        assert!(!build.join("media/leftover.png").exists());
+        // Emptied directories go along with their files
+        assert!(!build.join("m").exists());
+        assert!(!build.join("media/album").exists());
+        let mut changed = second.changed().to_vec();
+        changed.sort();
+        assert_eq!(
+            changed,
+            ["m/a/index.html", "media/album/b.png", "media/leftover.png"]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+
+use super::website_info::WebsiteInfo;
+use super::website_media::FeedItem;
+
+const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+/// A JSON Feed 1.1 document, built from the same items as the XML feeds.
+#[derive(Serialize)]
+pub struct JsonFeed<'a> {
+    version: &'static str,
+    title: String,
+    home_page_url: &'a str,
+    feed_url: String,
+    description: &'a str,
+    authors: Vec<JsonFeedAuthor<'a>>,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a> {
+    id: &'a str,
+    url: &'a str,
+    title: &'a str,
+    content_html: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    summary: &'a str,
+    image: &'a str,
+    date_published: &'a str,
+    /// The album followed by the item's tags.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<&'a str>,
+    attachments: Vec<JsonFeedAttachment<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAttachment<'a> {
+    url: &'a str,
+    mime_type: &'a str,
+    size_in_bytes: u64,
+}
+
+impl<'a> JsonFeed<'a> {
+    /// `home_page_url` is the page the feed belongs to, ending in `/`, and
+    /// `feed_file` the name of the feed inside it.
+    pub fn new(
+        info: &'a WebsiteInfo,
+        title: String,
+        home_page_url: &'a str,
+        feed_file: &str,
+        items: &[&'a FeedItem],
+    ) -> Self {
+        Self {
+            version: JSON_FEED_VERSION,
+            title,
+            home_page_url,
+            feed_url: format!("{}{}", home_page_url, feed_file),
+            description: &info.description,
+            authors: vec![JsonFeedAuthor { name: &info.author }],
+            items: items
+                .iter()
+                .map(|item| JsonFeedItem {
+                    id: &item.guid,
+                    url: &item.link,
+                    title: &item.title,
+                    content_html: &item.content,
+                    summary: &item.description,
+                    image: &item.thumb_url,
+                    date_published: &item.published,
+                    tags: item
+                        .album
+                        .iter()
+                        .chain(&item.tags)
+                        .map(String::as_str)
+                        .collect(),
+                    attachments: vec![JsonFeedAttachment {
+                        url: &item.enclosure_url,
+                        mime_type: item.mime_type,
+                        size_in_bytes: item.enclosure_length,
+                    }],
+                })
+                .collect(),
+        }
+    }
+}
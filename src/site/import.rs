@@ -0,0 +1,315 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use super::media_date::extract_date;
+use super::media_library::{MediaLibrary, MediaLibraryError, MetaMedia, hash_file};
+use super::website_info::Timezone;
+use super::website_media::is_supported_media;
+
+/// How `clutterlog add` brings files into `media/`.
+#[derive(Default)]
+pub struct ImportOptions {
+    /// Album (subdirectory of `media/`) to import into, `None` for the top level.
+    pub album: Option<String>,
+    /// Move the files instead of copying them.
+    pub move_files: bool,
+    /// Descend into subdirectories of the given directories.
+    pub recursive: bool,
+    /// Write a sidecar `.txt` stub for files that don't come with one.
+    pub sidecar_stubs: bool,
+    /// Inclusive range on the files' extracted local date.
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+pub struct ImportReport {
+    /// `(source, name)` of every imported file, `name` relative to `media/`.
+    pub imported: Vec<(PathBuf, String)>,
+    /// Files whose contents are already in the library.
+    pub duplicates: usize,
+    /// Files dated outside the requested range.
+    pub out_of_range: usize,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} imported, {} duplicates skipped, {} outside the date range",
+            self.imported.len(),
+            self.duplicates,
+            self.out_of_range
+        )
+    }
+}
+
+/// Copies (or moves) media files from outside the site into `media_path` and
+/// records them in the library. Dates are extracted from the source files, before
+/// copying resets their timestamps.
+pub fn import_media(
+    library: &mut MediaLibrary,
+    media_path: &Path,
+    sources: &[PathBuf],
+    options: &ImportOptions,
+    site_timezone: Timezone,
+) -> Result<ImportReport, MediaLibraryError> {
+    if let Some(album) = &options.album
+        && !Path::new(album)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(MediaLibraryError::InvalidAlbum(album.clone()));
+    }
+
+    let mut files = Vec::new();
+    for source in sources {
+        collect_files(source, options.recursive, true, &mut files)?;
+    }
+
+    let dest_dir = match &options.album {
+        Some(album) => media_path.join(album),
+        None => media_path.to_path_buf(),
+    };
+    fs::create_dir_all(&dest_dir).map_err(|e| MediaLibraryError::Io(dest_dir.clone(), e))?;
+
+    let mut known_hashes: HashSet<String> = library
+        .entries
+        .iter()
+        .map(|e| e.hash.clone())
+        .filter(|hash| !hash.is_empty())
+        .collect();
+    let mut report = ImportReport {
+        imported: Vec::new(),
+        duplicates: 0,
+        out_of_range: 0,
+    };
+
+    // Files copied before an error are recorded all the same, keeping the dates
+    // read from the originals rather than from the copies
+    let result = (|| {
+        for source in files {
+            let hash = hash_file(&source).map_err(|e| MediaLibraryError::Io(source.clone(), e))?;
+            if known_hashes.contains(&hash) {
+                report.duplicates += 1;
+                continue;
+            }
+
+            let date = extract_date(&source, site_timezone);
+            let in_range = NaiveDateTime::parse_from_str(&date.datetime, "%Y-%m-%dT%H:%M:%S")
+                .is_ok_and(|datetime| {
+                    options.from.is_none_or(|from| datetime >= from)
+                        && options.to.is_none_or(|to| datetime <= to)
+                });
+            if !in_range {
+                report.out_of_range += 1;
+                continue;
+            }
+
+            let name = unique_name(library, &dest_dir, options.album.as_deref(), &source);
+            let dest = media_path.join(&name);
+            transfer(&source, &dest, options.move_files)?;
+
+            library.entries.push(MetaMedia {
+                name: name.clone(),
+                slug: String::new(),
+                datetime: date.datetime,
+                timezone: date.timezone,
+                hash: hash.clone(),
+                date_source: Some(date.source),
+                tags: Vec::new(),
+                title: None,
+                description: None,
+                pinned: false,
+                hidden: false,
+                draft: false,
+                publish_after: None,
+            });
+            known_hashes.insert(hash);
+
+            // Bring the sidecar along, or start one
+            let source_sidecar = source.with_extension("txt");
+            let dest_sidecar = dest.with_extension("txt");
+            if source_sidecar.is_file() {
+                transfer(&source_sidecar, &dest_sidecar, options.move_files)?;
+            } else if options.sidecar_stubs {
+                let stem = dest.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+                fs::write(&dest_sidecar, format!("{}\n\n", stem))
+                    .map_err(|e| MediaLibraryError::Io(dest_sidecar.clone(), e))?;
+            }
+            report.imported.push((source, name));
+        }
+        Ok(())
+    })();
+
+    library.save()?;
+    result.map(|()| report)
+}
+
+/// Expands `path` into the supported media files it names. Directories given on
+/// the command line are read one level deep, or fully with `recursive`.
+fn collect_files(
+    path: &Path,
+    recursive: bool,
+    top_level: bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), MediaLibraryError> {
+    if !path.is_dir() {
+        if !path.exists() {
+            return Err(MediaLibraryError::Io(
+                path.to_path_buf(),
+                io::Error::from(io::ErrorKind::NotFound),
+            ));
+        }
+        if is_supported_media(path) {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+    if !top_level && !recursive {
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .map_err(|e| MediaLibraryError::Io(path.to_path_buf(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| !n.starts_with('.'))
+        })
+        .collect();
+    entries.sort();
+
+    for entry in entries {
+        collect_files(&entry, recursive, false, files)?;
+    }
+    Ok(())
+}
+
+/// Picks a free `media/`-relative name for `source` inside `dest_dir`, appending
+/// `-2`, `-3`, ... to the stem when it's taken. Stems are compared rather than
+/// whole names, since a sidecar is named after the stem: `a.jpg` next to `a.png`
+/// would pick up its `a.txt`.
+fn unique_name(
+    library: &MediaLibrary,
+    dest_dir: &Path,
+    album: Option<&str>,
+    source: &Path,
+) -> String {
+    let stem = source
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("media");
+    let extension = source.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+    let prefix = album.map(|album| format!("{}/", album)).unwrap_or_default();
+    let mut taken: HashSet<String> = fs::read_dir(dest_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    Path::new(&entry.file_name())
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .map(str::to_lowercase)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    taken.extend(library.entries.iter().filter_map(|e| {
+        let file_name = e.name.strip_prefix(&prefix)?;
+        let stem = Path::new(file_name).file_stem()?.to_str()?;
+        (!file_name.contains('/')).then(|| stem.to_lowercase())
+    }));
+
+    let mut n = 1;
+    loop {
+        let candidate = match n {
+            1 => stem.to_string(),
+            _ => format!("{}-{}", stem, n),
+        };
+        if !taken.contains(&candidate.to_lowercase()) {
+            return format!("{}{}.{}", prefix, candidate, extension);
+        }
+        n += 1;
+    }
+}
+
+/// Copies `source` to `dest`, or moves it. Moves fall back to copy and delete
+/// across filesystems.
+fn transfer(source: &Path, dest: &Path, move_file: bool) -> Result<(), MediaLibraryError> {
+    if move_file && fs::rename(source, dest).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(source, dest).map_err(|e| MediaLibraryError::Io(dest.to_path_buf(), e))?;
+    if move_file {
+        fs::remove_file(source).map_err(|e| MediaLibraryError::Io(source.to_path_buf(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_names_nothing_else_uses() {
+        let site = tempfile::tempdir().unwrap();
+        let media = site.path().join("media");
+        for (name, contents) in [
+            ("proj/a.png", "in album"),
+            ("proj/b.jpg", "other extension"),
+            ("proj/d.txt", "sidecar only"),
+            ("c.png", "outside the album"),
+        ] {
+            fs::create_dir_all(media.join(name).parent().unwrap()).unwrap();
+            fs::write(media.join(name), contents).unwrap();
+        }
+        let mut library = MediaLibrary::new(site.path()).unwrap();
+        library
+            .update_metadata(&media, Timezone::default())
+            .unwrap();
+
+        let sources = site.path().join("camera");
+        fs::create_dir_all(&sources).unwrap();
+        let files: Vec<PathBuf> = ["a.png", "b.png", "c.png", "d.png", "A.gif"]
+            .iter()
+            .map(|name| {
+                let path = sources.join(name);
+                fs::write(&path, format!("new {}", name)).unwrap();
+                path
+            })
+            .collect();
+
+        let options = ImportOptions {
+            album: Some("proj".to_string()),
+            ..ImportOptions::default()
+        };
+        let report =
+            import_media(&mut library, &media, &files, &options, Timezone::default()).unwrap();
+        let names: Vec<&str> = report
+            .imported
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "proj/a-2.png",
+                "proj/b-2.png",
+                "proj/c.png",
+                "proj/d-2.png",
+                "proj/A-3.gif",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(media.join("proj/a.png")).unwrap(),
+            "in album"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+// Injected by `clutterlog server --watch`: reloads the page after a rebuild, swaps
+// the stylesheets when only they changed, and shows build errors over the page.
+(function () {
+    "use strict";
+
+    var overlay = null;
+
+    function showError(message) {
+        if (!overlay) {
+            overlay = document.createElement("div");
+            overlay.style.cssText =
+                "position:fixed;inset:0;z-index:2147483647;overflow:auto;padding:32px;" +
+                "background:rgba(20,0,0,0.92);color:#fdd;font:14px/1.5 monospace;" +
+                "white-space:pre-wrap";
+            document.body.appendChild(overlay);
+        }
+        overlay.textContent = "clutterlog build failed\n\n" + message;
+    }
+
+    function hideError() {
+        if (overlay) {
+            overlay.remove();
+            overlay = null;
+        }
+    }
+
+    function refreshStylesheets() {
+        document.querySelectorAll('link[rel="stylesheet"]').forEach(function (link) {
+            var url = new URL(link.href);
+            url.searchParams.set("reload", Date.now());
+            link.href = url.href;
+        });
+    }
+
+    var events = new EventSource("/__clutterlog/events");
+
+    events.addEventListener("reload", function () {
+        location.reload();
+    });
+
+    events.addEventListener("css", function () {
+        hideError();
+        refreshStylesheets();
+    });
+
+    events.addEventListener("build-error", function (event) {
+        showError(JSON.parse(event.data));
+    });
+})();
@@ -0,0 +1,135 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::web::Bytes;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+/// Path the injected client connects to for server-sent events.
+pub const EVENTS_PATH: &str = "/__clutterlog/events";
+/// Path the client script is served from.
+pub const CLIENT_PATH: &str = "/__clutterlog/live-reload.js";
+pub const CLIENT_JS: &str = include_str!("live_reload.js");
+
+/// How long browsers wait before reconnecting after losing the event stream, in
+/// milliseconds.
+const RETRY_MS: u32 = 1000;
+
+/// Tells the browsers previewing the site what the last rebuild did, over
+/// server-sent events. Cheap to clone, every clone talks to the same browsers.
+#[derive(Clone, Default)]
+pub struct LiveReload {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: Vec<UnboundedSender<Bytes>>,
+    /// Message of the last build, if it failed. Sent to every browser that
+    /// connects until a build succeeds.
+    error: Option<String>,
+}
+
+enum Event {
+    /// Something other than stylesheets changed, reload the page.
+    Reload,
+    /// Only stylesheets changed, swap them without losing the page's state.
+    Css,
+    BuildError(String),
+}
+
+impl Event {
+    fn to_bytes(&self) -> Bytes {
+        let (name, data) = match self {
+            Event::Reload => ("reload", String::new()),
+            Event::Css => ("css", String::new()),
+            // Encoded as a JSON string, since event data can't span lines
+            Event::BuildError(message) => (
+                "build-error",
+                serde_json::to_string(message).unwrap_or_default(),
+            ),
+        };
+        Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+    }
+}
+
+impl LiveReload {
+    /// Opens an event stream for a newly connected browser.
+    pub fn subscribe(&self) -> EventStream {
+        let (sender, receiver) = unbounded_channel();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = sender.send(Bytes::from(format!("retry: {}\n\n", RETRY_MS)));
+        if let Some(error) = &state.error {
+            let _ = sender.send(Event::BuildError(error.clone()).to_bytes());
+        }
+        state.clients.push(sender);
+        EventStream { receiver }
+    }
+
+    /// Reports a successful build that wrote or removed `changed_files`.
+    pub fn build_succeeded(&self, changed_files: &[String]) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // A page showing the error overlay is reloaded even if nothing changed
+        let had_error = state.error.take().is_some();
+        let event = if had_error {
+            Event::Reload
+        } else if changed_files.is_empty() {
+            return;
+        } else if changed_files.iter().all(|file| file.ends_with(".css")) {
+            Event::Css
+        } else {
+            Event::Reload
+        };
+        broadcast(&mut state, event);
+    }
+
+    pub fn build_failed(&self, message: String) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.error = Some(message.clone());
+        broadcast(&mut state, Event::BuildError(message));
+    }
+
+    /// Ends every open event stream, for the server to shut down.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clients.clear();
+    }
+}
+
+/// Sends `event` to every connected browser, forgetting the ones that went away.
+fn broadcast(state: &mut State, event: Event) {
+    let bytes = event.to_bytes();
+    state
+        .clients
+        .retain(|client| client.send(bytes.clone()).is_ok());
+}
+
+/// The body of an event stream response, open until the browser disconnects.
+pub struct EventStream {
+    receiver: UnboundedReceiver<Bytes>,
+}
+
+impl MessageBody for EventStream {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.receiver.poll_recv(cx).map(|event| event.map(Ok))
+    }
+}
+
+/// Adds the client script to an HTML page, right before `</body>`.
+pub fn inject_client(html: &str) -> String {
+    let script = format!("<script src=\"{}\"></script>\n", CLIENT_PATH);
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], script, &html[index..]),
+        None => format!("{}{}", html, script),
+    }
+}
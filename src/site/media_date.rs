@@ -0,0 +1,868 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::website_info::Timezone;
+
+/// Where an item's date came from, recorded in `metamedia.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DateSource {
+    /// EXIF `DateTimeOriginal`/`DateTimeDigitized`/`DateTime`.
+    Exif,
+    /// XMP packet embedded in a PNG.
+    Xmp,
+    /// PNG `Creation Time` text chunk.
+    PngText,
+    /// Date written in a GIF comment extension.
+    GifComment,
+    /// MP4/MOV `mvhd` creation time.
+    Mp4,
+    /// Matroska/WebM `DateUTC`.
+    Matroska,
+    /// Oldest of the file's created/modified times.
+    Filesystem,
+    /// Set with `clutterlog edit`.
+    Manual,
+}
+
+/// Containers kamadak-exif can read EXIF from.
+const EXIF_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "webp", "png", "tiff", "tif", "heic", "heif", "avif",
+];
+
+/// Seconds between the QuickTime epoch (1904-01-01) and the Unix epoch.
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+/// Seconds between the Unix epoch and the Matroska epoch (2001-01-01).
+const MATROSKA_EPOCH_OFFSET: i64 = 978_307_200;
+
+/// A date read from a file, in whichever form the source stores it.
+enum Captured {
+    /// Wall-clock time with no known UTC offset (plain EXIF, most free-form text).
+    Local(NaiveDateTime),
+    /// Wall-clock time with its UTC offset.
+    Zoned(DateTime<FixedOffset>),
+    /// A point in time with no local context (container and filesystem times).
+    Instant(DateTime<Utc>),
+}
+
+type DateExtractor = fn(&Path) -> Option<Captured>;
+
+/// An extracted date, as stored in `metamedia.toml`.
+pub struct ExtractedDate {
+    /// Local wall-clock time, `%Y-%m-%dT%H:%M:%S`.
+    pub datetime: String,
+    /// UTC offset of `datetime` (e.g. `+02:00`), `None` if the source didn't say.
+    pub timezone: Option<String>,
+    pub source: DateSource,
+}
+
+/// Finds the best available date for a media file. Dates embedded in the file are
+/// tried first, most reliable first, since filesystem times don't survive a git
+/// clone. Falls back to the oldest filesystem timestamp.
+///
+/// Sources that only know the instant (videos, filesystem) are converted to
+/// `site_timezone`, the timezone from `site.toml`, with the offset in effect at
+/// that instant.
+pub fn extract_date(path: &Path, site_timezone: Timezone) -> ExtractedDate {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    let mut extractors: Vec<(DateSource, DateExtractor)> = Vec::new();
+    if EXIF_EXTENSIONS.contains(&extension.as_str()) {
+        extractors.push((DateSource::Exif, extract_exif_date));
+    }
+    match extension.as_str() {
+        "png" | "apng" => {
+            extractors.push((DateSource::Xmp, extract_png_xmp_date));
+            extractors.push((DateSource::PngText, extract_png_text_date));
+        }
+        "gif" => extractors.push((DateSource::GifComment, extract_gif_comment_date)),
+        "mp4" | "mov" => extractors.push((DateSource::Mp4, extract_mp4_date)),
+        "mkv" | "webm" => extractors.push((DateSource::Matroska, extract_matroska_date)),
+        _ => {}
+    }
+
+    let found = extractors
+        .into_iter()
+        .find_map(|(source, extract)| extract(path).map(|date| (date, source)));
+    let (captured, source) = match found {
+        Some(found) => found,
+        None => (
+            extract_filesystem_date(path).unwrap_or(Captured::Instant(DateTime::UNIX_EPOCH)),
+            DateSource::Filesystem,
+        ),
+    };
+
+    let (local, offset) = match captured {
+        Captured::Local(naive) => (naive, None),
+        Captured::Zoned(date) => (date.naive_local(), Some(*date.offset())),
+        Captured::Instant(date) => {
+            let offset = site_timezone.offset_at(date);
+            (date.with_timezone(&offset).naive_local(), Some(offset))
+        }
+    };
+
+    ExtractedDate {
+        datetime: local.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        timezone: offset.map(|offset| offset.to_string()),
+        source,
+    }
+}
+
+/// Parses a UTC offset as written in `metamedia.toml` and `site.toml`: `+02:00`,
+/// `-0530`, or `UTC`/`Z`.
+pub fn parse_offset(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("utc") || text.eq_ignore_ascii_case("z") {
+        return FixedOffset::east_opt(0);
+    }
+    text.parse().ok()
+}
+
+/// Parses a date given on the command line: `2024-03-01T23:30:00`, with a space
+/// instead of the `T`, or just `2024-03-01` (midnight). A trailing UTC offset
+/// (`2024-03-01T23:30:00+02:00`) is returned separately.
+pub fn parse_datetime_arg(text: &str) -> Result<(NaiveDateTime, Option<FixedOffset>), String> {
+    let text = text.trim();
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Ok((date.naive_local(), Some(*date.offset())));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok((datetime, None));
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok((date.and_time(NaiveTime::MIN), None));
+    }
+
+    Err(format!(
+        "invalid date '{}', expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS",
+        text
+    ))
+}
+
+/// Like `parse_datetime_arg`, but a bare date means the end of that day, so it
+/// can close an inclusive range.
+pub fn parse_range_end(text: &str) -> Result<NaiveDateTime, String> {
+    match NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d") {
+        Ok(date) => date
+            .and_hms_opt(23, 59, 59)
+            .ok_or_else(|| format!("invalid date '{}'", text)),
+        Err(_) => parse_datetime_arg(text).map(|(datetime, _)| datetime),
+    }
+}
+
+/// Parses a signed duration such as `+2h`, `-1d12h` or `+90s`. Units are `d`, `h`,
+/// `m` and `s`.
+pub fn parse_shift(text: &str) -> Result<TimeDelta, String> {
+    let invalid = || {
+        format!(
+            "invalid shift '{}', expected something like +2h or -1d30m",
+            text
+        )
+    };
+
+    let text = text.trim();
+    let (sign, rest) = match text.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, text.strip_prefix('+').unwrap_or(text)),
+    };
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: i64 = number.parse().map_err(|_| invalid())?;
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+
+    TimeDelta::try_seconds(sign * seconds).ok_or_else(invalid)
+}
+
+fn extract_filesystem_date(path: &Path) -> Option<Captured> {
+    let metadata = fs::metadata(path).ok()?;
+    [metadata.created(), metadata.modified()]
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(DateTime::<Utc>::from)
+        .min()
+        .map(Captured::Instant)
+}
+
+/// Attempts to extract a date from EXIF metadata embedded in an image file.
+/// Checks DateTimeOriginal, DateTimeDigitized, and DateTime fields in that order,
+/// each with its matching OffsetTime* field when the camera wrote one.
+/// Returns `None` for missing EXIF data or parse errors.
+fn extract_exif_date(path: &Path) -> Option<Captured> {
+    let file = File::open(path).ok()?;
+    let mut reader = BufReader::new(file);
+    let exif_data = exif::Reader::new().read_from_container(&mut reader).ok()?;
+
+    let date_tags = [
+        (exif::Tag::DateTimeOriginal, exif::Tag::OffsetTimeOriginal),
+        (exif::Tag::DateTimeDigitized, exif::Tag::OffsetTimeDigitized),
+        (exif::Tag::DateTime, exif::Tag::OffsetTime),
+    ];
+
+    for (date_tag, offset_tag) in &date_tags {
+        let Some(field) = exif_data.get_field(*date_tag, exif::In::PRIMARY) else {
+            continue;
+        };
+        let value = field.display_value().to_string();
+        // EXIF dates are formatted as "YYYY-MM-DD HH:MM:SS"
+        let Ok(naive) = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S") else {
+            continue;
+        };
+
+        // Offsets are ASCII strings like "+02:00"
+        let offset = exif_data
+            .get_field(*offset_tag, exif::In::PRIMARY)
+            .and_then(|field| match &field.value {
+                exif::Value::Ascii(values) => values.first(),
+                _ => None,
+            })
+            .and_then(|bytes| parse_offset(&String::from_utf8_lossy(bytes)));
+
+        return Some(
+            match offset.and_then(|o| naive.and_local_timezone(o).single()) {
+                Some(zoned) => Captured::Zoned(zoned),
+                None => Captured::Local(naive),
+            },
+        );
+    }
+
+    None
+}
+
+// MP4 / MOV
+
+fn extract_mp4_date(path: &Path) -> Option<Captured> {
+    read_mp4_date(&mut BufReader::new(File::open(path).ok()?))
+}
+
+/// Reads the creation time from the movie header (`moov/mvhd`).
+fn read_mp4_date<R: Read + Seek>(reader: &mut R) -> Option<Captured> {
+    let file_end = reader.seek(SeekFrom::End(0)).ok()?;
+    reader.seek(SeekFrom::Start(0)).ok()?;
+
+    let moov_end = find_mp4_box(reader, b"moov", file_end)?;
+    find_mp4_box(reader, b"mvhd", moov_end)?;
+
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version).ok()?;
+    let seconds = if version[0] == 1 {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).ok()?;
+        i64::try_from(u64::from_be_bytes(buf)).ok()?
+    } else {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).ok()?;
+        i64::from(u32::from_be_bytes(buf))
+    };
+
+    // Encoders that don't know the time write 0 (1904-01-01)
+    let unix = seconds - QUICKTIME_EPOCH_OFFSET;
+    if unix <= 0 {
+        return None;
+    }
+    DateTime::from_timestamp(unix, 0).map(Captured::Instant)
+}
+
+/// Scans sibling boxes up to `end` for one of type `kind`. On success the reader
+/// is positioned at the box's payload and the payload's end offset is returned.
+fn find_mp4_box<R: Read + Seek>(reader: &mut R, kind: &[u8; 4], end: u64) -> Option<u64> {
+    loop {
+        let start = reader.stream_position().ok()?;
+        if start + 8 > end {
+            return None;
+        }
+
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).ok()?;
+        let mut size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large).ok()?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        } else if size == 0 {
+            // Box extends to the end of its parent
+            size = end - start;
+        }
+        if size < header_len {
+            return None;
+        }
+
+        let box_end = start + size;
+        if &header[4..] == kind {
+            return Some(box_end.min(end));
+        }
+        reader.seek(SeekFrom::Start(box_end)).ok()?;
+    }
+}
+
+// Matroska / WebM
+
+const EBML_SEGMENT: u64 = 0x1853_8067;
+const EBML_INFO: u64 = 0x1549_A966;
+const EBML_DATE_UTC: u64 = 0x4461;
+const EBML_CLUSTER: u64 = 0x1F43_B675;
+
+fn extract_matroska_date(path: &Path) -> Option<Captured> {
+    read_matroska_date(&mut BufReader::new(File::open(path).ok()?))
+}
+
+/// Reads `Segment/Info/DateUTC`, nanoseconds since 2001-01-01 UTC.
+fn read_matroska_date<R: Read + Seek>(reader: &mut R) -> Option<Captured> {
+    // Skip the EBML header, then enter the segment
+    let (_, header_size) = read_ebml_element(reader)?;
+    reader
+        .seek_relative(i64::try_from(header_size?).ok()?)
+        .ok()?;
+    let (id, _) = read_ebml_element(reader)?;
+    if id != EBML_SEGMENT {
+        return None;
+    }
+
+    // The segment info comes before the first cluster in practice
+    let info_size = loop {
+        let (id, size) = read_ebml_element(reader)?;
+        match id {
+            EBML_INFO => break size?,
+            EBML_CLUSTER => return None,
+            _ => reader.seek_relative(i64::try_from(size?).ok()?).ok()?,
+        }
+    };
+
+    let mut consumed = 0;
+    while consumed < info_size {
+        let before = reader.stream_position().ok()?;
+        let (id, size) = read_ebml_element(reader)?;
+        let size = size?;
+        if id == EBML_DATE_UTC && size == 8 {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf).ok()?;
+            let nanos = i64::from_be_bytes(buf);
+            let seconds = nanos.div_euclid(1_000_000_000) + MATROSKA_EPOCH_OFFSET;
+            return DateTime::from_timestamp(seconds, 0).map(Captured::Instant);
+        }
+        reader.seek_relative(i64::try_from(size).ok()?).ok()?;
+        consumed += reader.stream_position().ok()? - before;
+    }
+
+    None
+}
+
+/// Reads an element header: its ID (marker bits kept, as IDs are usually written)
+/// and its data size, `None` for the "unknown size" value.
+fn read_ebml_element<R: Read>(reader: &mut R) -> Option<(u64, Option<u64>)> {
+    let (id, _) = read_ebml_vint(reader, true)?;
+    let (size, unknown) = read_ebml_vint(reader, false)?;
+    Some((id, (!unknown).then_some(size)))
+}
+
+/// Reads an EBML variable-length integer. Returns the value and whether all of
+/// its value bits were set.
+fn read_ebml_vint<R: Read>(reader: &mut R, keep_marker: bool) -> Option<(u64, bool)> {
+    let mut first = [0u8; 1];
+    reader.read_exact(&mut first).ok()?;
+    let length = first[0].leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+
+    let mut value = if keep_marker {
+        u64::from(first[0])
+    } else {
+        u64::from(first[0]) & (0xFF >> length)
+    };
+    let mut rest = [0u8; 7];
+    reader.read_exact(&mut rest[..length - 1]).ok()?;
+    for byte in &rest[..length - 1] {
+        value = (value << 8) | u64::from(*byte);
+    }
+
+    let all_ones = (1u64 << (7 * length)) - 1;
+    let unknown = !keep_marker && value == all_ones;
+    Some((value, unknown))
+}
+
+// PNG
+
+/// Returns the decoded contents of every uncompressed `tEXt`/`iTXt` chunk as
+/// `(keyword, text)` pairs, up to the image data.
+fn read_png_text_chunks<R: Read + Seek>(reader: &mut R) -> Option<Vec<(String, String)>> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature).ok()?;
+    if &signature != b"\x89PNG\r\n\x1a\n" {
+        return None;
+    }
+
+    let mut chunks = Vec::new();
+    let mut header = [0u8; 8];
+    while reader.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..] {
+            b"tEXt" | b"iTXt" => {
+                // The length comes from the file, so it's only trusted as far as the
+                // file actually goes
+                let mut data = Vec::new();
+                reader.take(length as u64).read_to_end(&mut data).ok()?;
+                if data.len() != length {
+                    return None;
+                }
+                reader.seek_relative(4).ok()?;
+                if let Some(chunk) = parse_png_text(&header[4..], &data) {
+                    chunks.push(chunk);
+                }
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => reader.seek_relative(length as i64 + 4).ok()?,
+        }
+    }
+    Some(chunks)
+}
+
+fn parse_png_text(kind: &[u8], data: &[u8]) -> Option<(String, String)> {
+    let (keyword, rest) = split_at_nul(data)?;
+    let text = if kind == b"iTXt" {
+        // Compression flag, compression method, language tag, translated keyword
+        let (&compressed, rest) = rest.split_first()?;
+        if compressed != 0 {
+            return None;
+        }
+        let (_language, rest) = split_at_nul(rest.get(1..)?)?;
+        let (_translated, text) = split_at_nul(rest)?;
+        String::from_utf8_lossy(text).into_owned()
+    } else {
+        // tEXt is Latin-1
+        rest.iter().map(|&b| b as char).collect()
+    };
+    Some((String::from_utf8_lossy(keyword).into_owned(), text))
+}
+
+fn split_at_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let nul = data.iter().position(|&b| b == 0)?;
+    Some((&data[..nul], &data[nul + 1..]))
+}
+
+fn extract_png_xmp_date(path: &Path) -> Option<Captured> {
+    read_png_text_chunks(&mut BufReader::new(File::open(path).ok()?))?
+        .into_iter()
+        .filter(|(keyword, _)| keyword == "XML:com.adobe.xmp")
+        .find_map(|(_, xmp)| parse_xmp_date(&xmp))
+}
+
+fn extract_png_text_date(path: &Path) -> Option<Captured> {
+    read_png_text_chunks(&mut BufReader::new(File::open(path).ok()?))?
+        .into_iter()
+        .filter(|(keyword, _)| keyword == "Creation Time")
+        .find_map(|(_, text)| parse_loose_date(&text))
+}
+
+/// Looks for the capture date in an XMP packet, written either as an attribute
+/// (`xmp:CreateDate="..."`) or as an element (`<xmp:CreateDate>...</xmp:CreateDate>`).
+fn parse_xmp_date(xmp: &str) -> Option<Captured> {
+    const PROPERTIES: &[&str] = &[
+        "exif:DateTimeOriginal",
+        "photoshop:DateCreated",
+        "xmp:CreateDate",
+    ];
+
+    PROPERTIES.iter().find_map(|property| {
+        let attribute = format!("{}=\"", property);
+        let element = format!("<{}>", property);
+        let value = if let Some(start) = xmp.find(&attribute) {
+            let value = &xmp[start + attribute.len()..];
+            &value[..value.find('"')?]
+        } else {
+            let start = xmp.find(&element)?;
+            let value = &xmp[start + element.len()..];
+            &value[..value.find('<')?]
+        };
+        parse_loose_date(value)
+    })
+}
+
+// GIF
+
+fn extract_gif_comment_date(path: &Path) -> Option<Captured> {
+    read_gif_comment_date(&mut BufReader::new(File::open(path).ok()?))
+}
+
+/// Reads the comment extensions of a GIF and returns the first date found in them.
+fn read_gif_comment_date<R: Read + Seek>(reader: &mut R) -> Option<Captured> {
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header).ok()?;
+    if !header.starts_with(b"GIF") {
+        return None;
+    }
+    // Global color table
+    if header[10] & 0x80 != 0 {
+        reader
+            .seek_relative(3 * (1i64 << ((header[10] & 0x07) + 1)))
+            .ok()?;
+    }
+
+    loop {
+        let mut introducer = [0u8; 1];
+        reader.read_exact(&mut introducer).ok()?;
+        match introducer[0] {
+            // Extension
+            0x21 => {
+                let mut label = [0u8; 1];
+                reader.read_exact(&mut label).ok()?;
+                let data = read_gif_sub_blocks(reader)?;
+                if label[0] == 0xFE
+                    && let Some(date) = parse_loose_date(&String::from_utf8_lossy(&data))
+                {
+                    return Some(date);
+                }
+            }
+            // Image descriptor, followed by an optional local color table and the
+            // LZW-compressed image data
+            0x2C => {
+                let mut descriptor = [0u8; 9];
+                reader.read_exact(&mut descriptor).ok()?;
+                if descriptor[8] & 0x80 != 0 {
+                    reader
+                        .seek_relative(3 * (1i64 << ((descriptor[8] & 0x07) + 1)))
+                        .ok()?;
+                }
+                reader.seek_relative(1).ok()?;
+                read_gif_sub_blocks(reader)?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn read_gif_sub_blocks<R: Read>(reader: &mut R) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let mut size = [0u8; 1];
+        reader.read_exact(&mut size).ok()?;
+        if size[0] == 0 {
+            return Some(data);
+        }
+        let start = data.len();
+        data.resize(start + size[0] as usize, 0);
+        reader.read_exact(&mut data[start..]).ok()?;
+    }
+}
+
+// Free-form dates
+
+/// Parses a date written by a tool rather than a spec: RFC 3339, RFC 2822, EXIF
+/// style, or an ISO-like date somewhere inside a longer text. Only the first two
+/// carry an offset.
+fn parse_loose_date(text: &str) -> Option<Captured> {
+    let text = text.trim();
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(Captured::Zoned(date));
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(text) {
+        return Some(Captured::Zoned(date));
+    }
+
+    // Find the first "YYYY-MM-DD" or "YYYY:MM:DD" and read as much as possible from there
+    let bytes = text.as_bytes();
+    (0..bytes.len()).find_map(|i| {
+        let candidate = text.get(i..)?;
+        // chrono skips leading whitespace, which would cut the day short
+        if !candidate.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        let date_part = candidate.get(..10)?;
+        let date = NaiveDate::parse_from_str(date_part, "%Y-%m-%d")
+            .or_else(|_| NaiveDate::parse_from_str(date_part, "%Y:%m:%d"))
+            .ok()?;
+
+        let time = candidate
+            .get(11..19)
+            .filter(|_| matches!(candidate.as_bytes()[10], b' ' | b'T'))
+            .and_then(|time| NaiveTime::parse_from_str(time, "%H:%M:%S").ok());
+        Some(Captured::Local(match time {
+            Some(time) => date.and_time(time),
+            None => date.and_hms_opt(0, 0, 0)?,
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn describe(captured: Option<Captured>) -> Option<String> {
+        captured.map(|captured| match captured {
+            Captured::Local(datetime) => format!("local {}", datetime),
+            Captured::Zoned(datetime) => format!("zoned {}", datetime.to_rfc3339()),
+            Captured::Instant(datetime) => format!("instant {}", datetime.to_rfc3339()),
+        })
+    }
+
+    #[test]
+    fn parses_shifts() {
+        assert_eq!(parse_shift("+2h"), Ok(TimeDelta::hours(2)));
+        assert_eq!(parse_shift("90s"), Ok(TimeDelta::seconds(90)));
+        assert_eq!(
+            parse_shift("-1d12h30m"),
+            Ok(-(TimeDelta::days(1) + TimeDelta::hours(12) + TimeDelta::minutes(30)))
+        );
+        for invalid in ["", "+", "-", "2", "2x", "h", "+99999999999999999d"] {
+            assert!(parse_shift(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_datetime_args() {
+        let midnight = NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let evening = midnight + TimeDelta::minutes(23 * 60 + 30);
+
+        assert_eq!(parse_datetime_arg("2024-03-01"), Ok((midnight, None)));
+        assert_eq!(
+            parse_datetime_arg("2024-03-01T23:30:00"),
+            Ok((evening, None))
+        );
+        assert_eq!(
+            parse_datetime_arg(" 2024-03-01 23:30:00 "),
+            Ok((evening, None))
+        );
+        assert_eq!(parse_datetime_arg("2024-03-01T23:30"), Ok((evening, None)));
+        assert_eq!(
+            parse_datetime_arg("2024-03-01T23:30:00+02:00"),
+            Ok((evening, FixedOffset::east_opt(2 * 3600)))
+        );
+        assert!(parse_datetime_arg("2024-13-01").is_err());
+        assert!(parse_datetime_arg("yesterday").is_err());
+
+        assert_eq!(
+            parse_range_end("2024-03-01"),
+            Ok(midnight + TimeDelta::seconds(86_399))
+        );
+        assert_eq!(parse_range_end("2024-03-01T23:30:00"), Ok(evening));
+    }
+
+    #[test]
+    fn parses_loose_dates() {
+        assert_eq!(
+            describe(parse_loose_date("2024-03-01T10:20:30+02:00")).as_deref(),
+            Some("zoned 2024-03-01T10:20:30+02:00")
+        );
+        assert_eq!(
+            describe(parse_loose_date("Fri, 01 Mar 2024 10:20:30 -0500")).as_deref(),
+            Some("zoned 2024-03-01T10:20:30-05:00")
+        );
+        assert_eq!(
+            describe(parse_loose_date("Shot on 2024:03:01 10:20:30 by a scanner")).as_deref(),
+            Some("local 2024-03-01 10:20:30")
+        );
+        assert_eq!(
+            describe(parse_loose_date("made 2024-03-01, edited later")).as_deref(),
+            Some("local 2024-03-01 00:00:00")
+        );
+        assert_eq!(
+            describe(parse_loose_date("on 2024-03-15")).as_deref(),
+            Some("local 2024-03-15 00:00:00")
+        );
+        assert_eq!(describe(parse_loose_date("no date here")), None);
+        assert_eq!(describe(parse_loose_date("é 2024-02-30")), None);
+    }
+
+    #[test]
+    fn parses_xmp_dates() {
+        let attribute = r#"<rdf:Description xmp:CreateDate="2024-03-01T10:20:30"/>"#;
+        let element = "<exif:DateTimeOriginal>2024-03-01T10:20:30+01:00</exif:DateTimeOriginal>";
+        assert_eq!(
+            describe(parse_xmp_date(attribute)).as_deref(),
+            Some("local 2024-03-01 10:20:30")
+        );
+        assert_eq!(
+            describe(parse_xmp_date(element)).as_deref(),
+            Some("zoned 2024-03-01T10:20:30+01:00")
+        );
+        assert_eq!(describe(parse_xmp_date("<x:xmpmeta/>")), None);
+    }
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn reads_mp4_creation_time() {
+        let created = (1_700_000_000 + QUICKTIME_EPOCH_OFFSET) as u32;
+        let mut mvhd = vec![0, 0, 0, 0];
+        mvhd.extend_from_slice(&created.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 8]);
+
+        let mut moov = mp4_box(b"udta", &[]);
+        moov.extend(mp4_box(b"mvhd", &mvhd));
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend(mp4_box(b"moov", &moov));
+        assert_eq!(
+            describe(read_mp4_date(&mut Cursor::new(&file))).as_deref(),
+            Some("instant 2023-11-14T22:13:20+00:00")
+        );
+
+        // Unknown creation time
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend(mp4_box(b"moov", &mp4_box(b"mvhd", &[0; 12])));
+        assert_eq!(describe(read_mp4_date(&mut Cursor::new(&file))), None);
+
+        // A box claiming more than the file holds
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend_from_slice(&u32::MAX.to_be_bytes());
+        file.extend_from_slice(b"moov");
+        assert_eq!(describe(read_mp4_date(&mut Cursor::new(&file))), None);
+    }
+
+    #[test]
+    fn reads_ebml_vints() {
+        let read = |bytes: &[u8], keep_marker| read_ebml_vint(&mut Cursor::new(bytes), keep_marker);
+        assert_eq!(read(&[0x81], false), Some((1, false)));
+        assert_eq!(read(&[0x81], true), Some((0x81, false)));
+        assert_eq!(read(&[0x40, 0x02], false), Some((2, false)));
+        assert_eq!(read(&[0xFF], false), Some((0x7F, true)));
+        assert_eq!(
+            read(&[0x1A, 0x45, 0xDF, 0xA3], true),
+            Some((0x1A45_DFA3, false))
+        );
+        assert_eq!(read(&[0x00], false), None);
+        assert_eq!(read(&[0x40], false), None);
+    }
+
+    #[test]
+    fn reads_matroska_date() {
+        let nanos = (1_700_000_000 - MATROSKA_EPOCH_OFFSET) * 1_000_000_000;
+        let mut info = vec![0x2A, 0xD7, 0xB1, 0x83, 0x0F, 0x42, 0x40];
+        info.extend_from_slice(&[0x44, 0x61, 0x88]);
+        info.extend_from_slice(&nanos.to_be_bytes());
+
+        let mut file = vec![0x1A, 0x45, 0xDF, 0xA3, 0x82, 0x42, 0x86];
+        // Segment of unknown size, a void element, then the info
+        file.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0xFF]);
+        file.extend_from_slice(&[0xEC, 0x81, 0x00]);
+        file.extend_from_slice(&[0x15, 0x49, 0xA9, 0x66, 0x80 | info.len() as u8]);
+        file.extend_from_slice(&info);
+        assert_eq!(
+            describe(read_matroska_date(&mut Cursor::new(&file))).as_deref(),
+            Some("instant 2023-11-14T22:13:20+00:00")
+        );
+
+        // A cluster before any info
+        let mut file = vec![0x1A, 0x45, 0xDF, 0xA3, 0x80];
+        file.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0xFF]);
+        file.extend_from_slice(&[0x1F, 0x43, 0xB6, 0x75, 0x80]);
+        assert_eq!(describe(read_matroska_date(&mut Cursor::new(&file))), None);
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    #[test]
+    fn reads_png_text_chunks() {
+        let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+        file.extend(png_chunk(b"IHDR", &[0; 13]));
+        file.extend(png_chunk(b"tEXt", b"Creation Time\0caf\xe9 2024-03-01"));
+        file.extend(png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0en\0\0<xmp/>"));
+        // Compressed, skipped
+        file.extend(png_chunk(b"iTXt", b"Comment\0\x01\0\0\0x\x9c"));
+        file.extend(png_chunk(b"IDAT", &[0; 4]));
+        file.extend(png_chunk(b"tEXt", b"After\0image data"));
+
+        assert_eq!(
+            read_png_text_chunks(&mut Cursor::new(&file)),
+            Some(vec![
+                ("Creation Time".to_string(), "café 2024-03-01".to_string()),
+                ("XML:com.adobe.xmp".to_string(), "<xmp/>".to_string()),
+            ])
+        );
+
+        assert_eq!(read_png_text_chunks(&mut Cursor::new(b"GIF89a")), None);
+    }
+
+    #[test]
+    fn rejects_png_chunks_longer_than_the_file() {
+        let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+        file.extend_from_slice(&u32::MAX.to_be_bytes());
+        file.extend_from_slice(b"tEXt");
+        file.extend_from_slice(b"Creation Time\0");
+        assert_eq!(read_png_text_chunks(&mut Cursor::new(&file)), None);
+    }
+
+    #[test]
+    fn reads_gif_comments() {
+        // Header with a two-entry global color table
+        let mut file = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+        file.extend_from_slice(&[0; 6]);
+        // Graphic control extension
+        file.extend_from_slice(&[0x21, 0xF9, 0x04, 0, 0, 0, 0, 0x00]);
+        // Image descriptor, LZW minimum code size and image data
+        file.extend_from_slice(&[0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0x00]);
+        file.extend_from_slice(&[0x02, 0x02, 0x44, 0x01, 0x00]);
+        // Comment split across two sub-blocks
+        file.extend_from_slice(&[0x21, 0xFE, 0x08]);
+        file.extend_from_slice(b"Saved 20");
+        file.extend_from_slice(&[0x11]);
+        file.extend_from_slice(b"24-03-01 10:20:30");
+        file.extend_from_slice(&[0x00, 0x3B]);
+
+        assert_eq!(
+            describe(read_gif_comment_date(&mut Cursor::new(&file))).as_deref(),
+            Some("local 2024-03-01 10:20:30")
+        );
+
+        // Ends at the trailer without a comment
+        let file = b"GIF89a\x01\x00\x01\x00\x00\x00\x00\x3B";
+        assert_eq!(
+            describe(read_gif_comment_date(&mut Cursor::new(file))),
+            None
+        );
+    }
+}
@@ -1,19 +1,140 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs::{self, File};
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{FixedOffset, NaiveDateTime, TimeDelta};
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
-use super::website_media::SUPPORTED_EXTENSIONS;
+use super::build_cache::BuildCache;
+use super::media_date::{DateSource, extract_date};
+use super::website_info::Timezone;
+use super::website_media::{scan_media_files, slugify};
 
-const CLUTTERLOG_DIR: &str = ".clutterlog";
-const METAMEDIA_TOML: &str = "metamedia.toml";
+pub(super) const CLUTTERLOG_DIR: &str = ".clutterlog";
+pub(super) const METAMEDIA_TOML: &str = "metamedia.toml";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MetaMedia {
     pub name: String,
+    /// Permalink page slug (`m/<slug>/`), handed out the first time the file is
+    /// seen and kept from then on, renames included. Adding or removing other
+    /// files never moves a published page.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub slug: String,
+    /// Local wall-clock time the item was captured, `%Y-%m-%dT%H:%M:%S`.
     pub datetime: String,
+    /// UTC offset of `datetime` (e.g. `+02:00`). Entries without one use the site's
+    /// `timezone`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// SHA-1 of the file contents, used to recognise the file after a rename or move.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+    /// What `datetime` was read from. Missing for entries written by hand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_source: Option<DateSource>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Overrides the title from the filename or sidecar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Overrides the description from the sidecar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Set by hand: `update_metadata` keeps the entry as-is, even while its file is
+    /// missing from `media/`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// Never published, only shown by `server --drafts`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+    /// Work in progress, published once the flag is removed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub draft: bool,
+    /// Published by the first build after this time (`2024-03-01` or
+    /// `2024-03-01T09:00:00`, in the entry's timezone unless it carries an offset).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_after: Option<String>,
+}
+
+/// Which entries `MediaLibrary::edit` applies to. Both criteria must match when
+/// both are given.
+#[derive(Default)]
+pub struct Selection {
+    /// Names or glob patterns (`*`, `?`, `**`) relative to `media/`.
+    pub patterns: Vec<String>,
+    /// Inclusive range on the entries' local `datetime`.
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+impl Selection {
+    fn matches(&self, entry: &MetaMedia) -> bool {
+        let name_matches = self.patterns.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_match(pattern, &entry.name));
+
+        let in_range = if self.from.is_none() && self.to.is_none() {
+            true
+        } else {
+            match NaiveDateTime::parse_from_str(&entry.datetime, "%Y-%m-%dT%H:%M:%S") {
+                Ok(datetime) => {
+                    self.from.is_none_or(|from| datetime >= from)
+                        && self.to.is_none_or(|to| datetime <= to)
+                }
+                Err(_) => false,
+            }
+        };
+
+        name_matches && in_range
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.from.is_none() && self.to.is_none()
+    }
+}
+
+/// Changes applied by `MediaLibrary::edit`. `None` leaves a field untouched.
+#[derive(Default)]
+pub struct MetaEdit {
+    pub datetime: Option<NaiveDateTime>,
+    pub shift: Option<TimeDelta>,
+    pub timezone: Option<FixedOffset>,
+    /// An empty string clears the override.
+    pub title: Option<String>,
+    /// An empty string clears the override.
+    pub description: Option<String>,
+    pub pinned: Option<bool>,
+    /// Added unless a tag with the same slug is already there.
+    pub add_tags: Vec<String>,
+    /// Removed by slug, so spelling and case don't matter.
+    pub remove_tags: Vec<String>,
+    pub hidden: Option<bool>,
+    pub draft: Option<bool>,
+    /// An empty string clears the schedule.
+    pub publish_after: Option<String>,
+}
+
+impl MetaEdit {
+    fn changes_date(&self) -> bool {
+        self.datetime.is_some() || self.shift.is_some() || self.timezone.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.changes_date()
+            && self.title.is_none()
+            && self.description.is_none()
+            && self.pinned.is_none()
+            && self.add_tags.is_empty()
+            && self.remove_tags.is_empty()
+            && self.hidden.is_none()
+            && self.draft.is_none()
+            && self.publish_after.is_none()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,18 +145,24 @@ struct MetaMediaFile {
 
 pub struct UpdateReport {
     pub added: usize,
+    pub renamed: usize,
     pub removed: usize,
 }
 
 impl std::fmt::Display for UpdateReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} added, {} removed", self.added, self.removed)
+        write!(
+            f,
+            "{} added, {} renamed, {} removed",
+            self.added, self.renamed, self.removed
+        )
     }
 }
 
 pub struct MediaLibrary {
     pub entries: Vec<MetaMedia>,
     path: PathBuf,
+    site_path: PathBuf,
 }
 
 impl MediaLibrary {
@@ -51,6 +178,7 @@ impl MediaLibrary {
             Ok(Self {
                 entries: meta_file.media,
                 path: file_path,
+                site_path: site_path.to_path_buf(),
             })
         } else {
             fs::create_dir_all(&dir_path)
@@ -58,148 +186,290 @@ impl MediaLibrary {
             Ok(Self {
                 entries: Vec::new(),
                 path: file_path,
+                site_path: site_path.to_path_buf(),
             })
         }
     }
 
+    /// Syncs the entries with the files in `media_path`. `site_timezone` is used for
+    /// dates that don't come with a local time of their own.
     pub fn update_metadata(
         &mut self,
         media_path: &Path,
+        site_timezone: Timezone,
     ) -> Result<UpdateReport, MediaLibraryError> {
         let mut added: usize = 0;
+        let mut renamed: usize = 0;
 
         if !media_path.exists() {
             // Remove all entries since there's no media directory
             let removed = self.entries.len();
             self.entries.clear();
             self.save()?;
-            return Ok(UpdateReport { added, removed });
+            return Ok(UpdateReport {
+                added,
+                renamed,
+                removed,
+            });
         }
 
-        // Collect current media filenames
-        let mut current_files: Vec<String> = Vec::new();
-        let dir_entries = fs::read_dir(media_path)
-            .map_err(|e| MediaLibraryError::Io(media_path.to_path_buf(), e))?;
-
-        for entry in dir_entries {
-            let entry = entry.map_err(|e| MediaLibraryError::Io(media_path.to_path_buf(), e))?;
-            let path = entry.path();
+        // Collect current media names, relative to the media directory
+        let scanned: Vec<String> = scan_media_files(media_path)
+            .map_err(|e| MediaLibraryError::Io(media_path.to_path_buf(), e))?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let current_files: HashSet<&str> = scanned.iter().map(String::as_str).collect();
+
+        // Hash every file, reusing the build cache's hash while the size and mtime
+        // stay the same, so a file edited in place is still recognized once it's
+        // renamed.
+        let cache = BuildCache::load(&self.site_path);
+        let mut sources = BTreeMap::new();
+        for name in &scanned {
+            let file_path = media_path.join(name);
+            let source = cache
+                .hash_source(name, &file_path)
+                .map_err(|e| MediaLibraryError::Io(file_path, e))?;
+            sources.insert(name.clone(), source);
+        }
+        for entry in &mut self.entries {
+            if let Some(source) = sources.get(&entry.name) {
+                entry.hash = source.hash.clone();
+            }
+        }
+        let known: HashSet<String> = self.entries.iter().map(|e| e.name.clone()).collect();
 
-            if !path.is_file() {
+        // Add new files that aren't in metadata yet. A new file with the same
+        // contents as an entry whose file is gone was renamed or moved: it takes
+        // over that entry, keeping its date.
+        for filename in &scanned {
+            if known.contains(filename) {
                 continue;
             }
 
-            let extension = match path.extension().and_then(|ext| ext.to_str()) {
-                Some(ext) => ext.to_lowercase(),
-                None => continue,
-            };
+            let file_path = media_path.join(filename);
+            let hash = sources[filename].hash.clone();
+
+            let moved_entry = self.entries.iter_mut().find(|e| {
+                !e.hash.is_empty() && e.hash == hash && !current_files.contains(e.name.as_str())
+            });
+            match moved_entry {
+                Some(entry) => {
+                    entry.name = filename.clone();
+                    renamed += 1;
+                }
+                None => {
+                    let date = extract_date(&file_path, site_timezone);
+                    self.entries.push(MetaMedia {
+                        name: filename.clone(),
+                        slug: String::new(),
+                        datetime: date.datetime,
+                        timezone: date.timezone,
+                        hash,
+                        date_source: Some(date.source),
+                        tags: Vec::new(),
+                        title: None,
+                        description: None,
+                        pinned: false,
+                        hidden: false,
+                        draft: false,
+                        publish_after: None,
+                    });
+                    added += 1;
+                }
+            }
+        }
 
-            if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
-                continue;
+        // Remove stale entries for files that no longer exist. Pinned entries hold
+        // hand-set data, so they wait for their file to come back.
+        let before_len = self.entries.len();
+        self.entries
+            .retain(|e| e.pinned || current_files.contains(e.name.as_str()));
+        let removed = before_len - self.entries.len();
+
+        self.assign_slugs();
+
+        // Only a speedup for the next run, a cache that can't be written is skipped
+        let _ = cache.record_sources(sources);
+        self.save()?;
+
+        Ok(UpdateReport {
+            added,
+            renamed,
+            removed,
+        })
+    }
+
+    /// Applies `edit` to every entry matched by `selection` and saves the file.
+    /// Entries whose date is changed are pinned. Returns the names of the edited
+    /// entries.
+    pub fn edit(
+        &mut self,
+        selection: &Selection,
+        edit: &MetaEdit,
+    ) -> Result<Vec<String>, MediaLibraryError> {
+        if selection.is_empty() {
+            return Err(MediaLibraryError::NoSelection);
+        }
+        if edit.is_empty() {
+            return Err(MediaLibraryError::NoChanges);
+        }
+
+        let mut edited = Vec::new();
+        for entry in self.entries.iter_mut().filter(|e| selection.matches(e)) {
+            if let Some(datetime) = edit.datetime {
+                entry.datetime = datetime.format("%Y-%m-%dT%H:%M:%S").to_string();
+            }
+            if let Some(shift) = edit.shift {
+                let datetime = NaiveDateTime::parse_from_str(&entry.datetime, "%Y-%m-%dT%H:%M:%S")
+                    .map_err(|_| MediaLibraryError::InvalidDate(entry.name.clone()))?;
+                entry.datetime = (datetime + shift).format("%Y-%m-%dT%H:%M:%S").to_string();
+            }
+            if let Some(timezone) = edit.timezone {
+                entry.timezone = Some(timezone.to_string());
+            }
+            if edit.changes_date() {
+                entry.date_source = Some(DateSource::Manual);
+                entry.pinned = true;
             }
 
-            if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                current_files.push(filename.to_string());
+            if let Some(title) = &edit.title {
+                entry.title = (!title.is_empty()).then(|| title.clone());
+            }
+            if let Some(description) = &edit.description {
+                entry.description = (!description.is_empty()).then(|| description.clone());
+            }
+            if let Some(pinned) = edit.pinned {
+                entry.pinned = pinned;
             }
-        }
 
-        // Add new files that aren't in metadata yet
-        for filename in &current_files {
-            let already_exists = self.entries.iter().any(|e| e.name == *filename);
-            if !already_exists {
-                let file_path = media_path.join(filename);
-                let datetime = extract_oldest_date(&file_path);
-                self.entries.push(MetaMedia {
-                    name: filename.clone(),
-                    datetime,
-                });
-                added += 1;
+            let removed: Vec<String> = edit.remove_tags.iter().map(|t| slugify(t)).collect();
+            entry.tags.retain(|t| !removed.contains(&slugify(t)));
+            for tag in &edit.add_tags {
+                if !entry.tags.iter().any(|t| slugify(t) == slugify(tag)) {
+                    entry.tags.push(tag.clone());
+                }
+            }
+            if let Some(hidden) = edit.hidden {
+                entry.hidden = hidden;
             }
+            if let Some(draft) = edit.draft {
+                entry.draft = draft;
+            }
+            if let Some(publish_after) = &edit.publish_after {
+                entry.publish_after = (!publish_after.is_empty()).then(|| publish_after.clone());
+            }
+
+            edited.push(entry.name.clone());
         }
 
-        // Remove stale entries for files that no longer exist
-        let before_len = self.entries.len();
-        self.entries.retain(|e| current_files.contains(&e.name));
-        let removed = before_len - self.entries.len();
+        if edited.is_empty() {
+            return Err(MediaLibraryError::NoMatch);
+        }
 
         self.save()?;
-
-        Ok(UpdateReport { added, removed })
+        Ok(edited)
     }
 
-    pub fn get_datetime(&self, filename: &str) -> Option<&str> {
-        self.entries
+    /// Gives every entry without a slug one that no other entry holds, in name
+    /// order.
+    fn assign_slugs(&mut self) {
+        let mut used: HashSet<String> = self
+            .entries
             .iter()
-            .find(|e| e.name == filename)
-            .map(|e| e.datetime.as_str())
+            .filter(|e| !e.slug.is_empty())
+            .map(|e| e.slug.clone())
+            .collect();
+        let mut unassigned: Vec<&mut MetaMedia> = self
+            .entries
+            .iter_mut()
+            .filter(|e| e.slug.is_empty())
+            .collect();
+        unassigned.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in unassigned {
+            entry.slug = unique_slug(&entry.name, &mut used);
+        }
     }
 
-    fn save(&self) -> Result<(), MediaLibraryError> {
+    /// Looks up the stored metadata for a media item by its path relative to `media/`.
+    pub fn get(&self, filename: &str) -> Option<&MetaMedia> {
+        self.entries.iter().find(|e| e.name == filename)
+    }
+
+    pub(super) fn save(&self) -> Result<(), MediaLibraryError> {
         let meta_file = MetaMediaFile {
             media: self.entries.clone(),
         };
         let content = toml::to_string_pretty(&meta_file).map_err(MediaLibraryError::Serialize)?;
+        // Left alone when unchanged, `server --watch` rebuilds whenever it's written
+        if fs::read_to_string(&self.path).is_ok_and(|existing| existing == content) {
+            return Ok(());
+        }
         fs::write(&self.path, &content).map_err(|e| MediaLibraryError::Io(self.path.clone(), e))?;
         Ok(())
     }
 }
 
-fn extract_oldest_date(path: &Path) -> String {
-    let mut candidates: Vec<DateTime<Utc>> = Vec::new();
-
-    // Try EXIF metadata for supported image formats
-    if let Some(exif_dt) = extract_exif_date(path) {
-        candidates.push(exif_dt);
-    }
-
-    // Filesystem timestamps
-    if let Ok(metadata) = fs::metadata(path) {
-        if let Ok(created) = metadata.created() {
-            let dt: DateTime<Utc> = created.into();
-            candidates.push(dt);
-        }
-        if let Ok(modified) = metadata.modified() {
-            let dt: DateTime<Utc> = modified.into();
-            candidates.push(dt);
+/// Matches a `/`-separated name against a glob: `*` and `?` stay within a path
+/// segment, `**` also crosses `/`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[u8], name: &[u8]) -> bool {
+        match pattern {
+            [] => name.is_empty(),
+            [b'*', b'*', rest @ ..] => match rest.strip_prefix(b"/") {
+                // `**/` stands for whole segments, so what follows starts a segment
+                Some(rest) => (0..=name.len())
+                    .filter(|&i| i == 0 || name[i - 1] == b'/')
+                    .any(|i| matches(rest, &name[i..])),
+                None => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+            },
+            [b'*', rest @ ..] => (0..=name.len())
+                .take_while(|&i| i == 0 || name[i - 1] != b'/')
+                .any(|i| matches(rest, &name[i..])),
+            [b'?', rest @ ..] => {
+                name.first().is_some_and(|&c| c != b'/') && matches(rest, &name[1..])
+            }
+            [c, rest @ ..] => name.first() == Some(c) && matches(rest, &name[1..]),
         }
     }
 
-    match candidates.iter().min() {
-        Some(oldest) => oldest.format("%Y-%m-%dT%H:%M:%S").to_string(),
-        None => "1970-01-01T00:00:00".to_string(),
-    }
+    matches(pattern.as_bytes(), name.as_bytes())
 }
 
-/// Attempts to extract a date from EXIF metadata embedded in an image file.
-/// Checks DateTimeOriginal, DateTimeDigitized, and DateTime fields in that order.
-/// Returns `None` for unsupported formats, missing EXIF data, or parse errors.
-fn extract_exif_date(path: &Path) -> Option<DateTime<Utc>> {
-    let extension = path.extension()?.to_str()?.to_lowercase();
-    if !["jpg", "jpeg", "webp", "tiff", "tif"].contains(&extension.as_str()) {
-        return None;
+/// Slugifies `name` (without its extension), appending a counter if another item
+/// already took the slug.
+pub(super) fn unique_slug(name: &str, used: &mut HashSet<String>) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    let base = slugify(stem);
+    let mut slug = base.clone();
+    let mut counter = 2;
+    while !used.insert(slug.clone()) {
+        slug = format!("{}-{}", base, counter);
+        counter += 1;
     }
+    slug
+}
 
-    let file = File::open(path).ok()?;
-    let mut reader = BufReader::new(file);
-    let exif_data = exif::Reader::new().read_from_container(&mut reader).ok()?;
-
-    let date_tags = [
-        exif::Tag::DateTimeOriginal,
-        exif::Tag::DateTimeDigitized,
-        exif::Tag::DateTime,
-    ];
+/// Hex-encoded SHA-1 of a file's contents.
+pub(super) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha1::new();
+    let mut buffer = [0u8; 64 * 1024];
 
-    for tag in &date_tags {
-        if let Some(field) = exif_data.get_field(*tag, exif::In::PRIMARY) {
-            let value = field.display_value().to_string();
-            // EXIF dates are formatted as "YYYY-MM-DD HH:MM:SS"
-            if let Ok(naive) = NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S") {
-                return Some(naive.and_utc());
-            }
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
         }
+        hasher.update(&buffer[..read]);
     }
 
-    None
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
 }
 
 // Error
@@ -209,6 +479,16 @@ pub enum MediaLibraryError {
     Io(PathBuf, io::Error),
     Parse(PathBuf, Box<toml::de::Error>),
     Serialize(toml::ser::Error),
+    /// An edit was requested without naming any items or date range.
+    NoSelection,
+    /// An edit was requested without anything to change.
+    NoChanges,
+    /// The selection didn't match any entry.
+    NoMatch,
+    /// An entry's stored date can't be parsed, so it can't be shifted.
+    InvalidDate(String),
+    /// An import target that isn't a plain path inside `media/`.
+    InvalidAlbum(String),
 }
 
 impl std::fmt::Display for MediaLibraryError {
@@ -223,8 +503,266 @@ impl std::fmt::Display for MediaLibraryError {
             MediaLibraryError::Serialize(err) => {
                 write!(f, "failed to serialize media metadata: {}", err)
             }
+            MediaLibraryError::NoSelection => {
+                write!(
+                    f,
+                    "no items selected, pass item names/globs or a date range"
+                )
+            }
+            MediaLibraryError::NoChanges => {
+                write!(f, "nothing to change, pass at least one edit option")
+            }
+            MediaLibraryError::NoMatch => write!(f, "no media items matched the selection"),
+            MediaLibraryError::InvalidDate(name) => {
+                write!(f, "'{}' has an invalid datetime in metamedia.toml", name)
+            }
+            MediaLibraryError::InvalidAlbum(album) => {
+                write!(
+                    f,
+                    "invalid album '{}', expected a path inside media/",
+                    album
+                )
+            }
         }
     }
 }
 
 impl std::error::Error for MediaLibraryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("a.png", "a.png"));
+        assert!(!glob_match("a.png", "b.png"));
+        assert!(glob_match("*.png", "a.png"));
+        assert!(!glob_match("*.png", "album/a.png"));
+        assert!(glob_match("album/*.jpg", "album/first print.jpg"));
+        assert!(glob_match("?.png", "a.png"));
+        assert!(!glob_match("?.png", "ab.png"));
+        assert!(!glob_match("album?a.png", "album/a.png"));
+        assert!(glob_match("**", "album/sub/a.png"));
+        assert!(glob_match("**/*.png", "a.png"));
+        assert!(glob_match("**/*.png", "album/sub/a.png"));
+        assert!(glob_match("album/**/a.png", "album/a.png"));
+        assert!(glob_match("album/**/a.png", "album/sub/a.png"));
+        assert!(!glob_match("album/**/a.png", "album/sub/xa.png"));
+        assert!(glob_match("album/**", "album/sub/a.png"));
+        assert!(!glob_match("album/**", "other/a.png"));
+    }
+
+    #[test]
+    fn makes_unique_slugs() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_slug("Loose Sketch.png", &mut used), "loose-sketch");
+        assert_eq!(unique_slug("loose-sketch.jpg", &mut used), "loose-sketch-2");
+        assert_eq!(unique_slug("loose_sketch.mp4", &mut used), "loose-sketch-3");
+        assert_eq!(
+            unique_slug("loose-sketch-2.gif", &mut used),
+            "loose-sketch-2-2"
+        );
+        assert_eq!(
+            unique_slug("robot-arm/first.jpg", &mut used),
+            "robot-arm-first"
+        );
+        assert_eq!(
+            unique_slug("v1.2/notes.txt.png", &mut used),
+            "v1-2-notes-txt"
+        );
+        assert_eq!(unique_slug("!!!.png", &mut used), "item");
+        assert_eq!(unique_slug("???.png", &mut used), "item-2");
+    }
+
+    /// A site directory with `files` (path relative to `media/`, contents) in it.
+    fn site_with(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let site = tempfile::tempdir().unwrap();
+        for (name, contents) in files {
+            write_media(site.path(), name, contents);
+        }
+        site
+    }
+
+    fn write_media(site: &Path, name: &str, contents: &str) {
+        let path = site.join("media").join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn update(site: &Path) -> (MediaLibrary, UpdateReport) {
+        let mut library = MediaLibrary::new(site).unwrap();
+        let report = library
+            .update_metadata(&site.join("media"), Timezone::default())
+            .unwrap();
+        (library, report)
+    }
+
+    fn slug_of(library: &MediaLibrary, name: &str) -> String {
+        library.get(name).unwrap().slug.clone()
+    }
+
+    #[test]
+    fn keeps_slugs_when_files_come_and_go() {
+        let site = site_with(&[("sketch.png", "png"), ("b.gif", "gif"), ("b.png", "b")]);
+        let (library, _) = update(site.path());
+        assert_eq!(slug_of(&library, "sketch.png"), "sketch");
+        assert_eq!(slug_of(&library, "b.gif"), "b");
+        assert_eq!(slug_of(&library, "b.png"), "b-2");
+
+        // A file sorting before an existing one with the same stem
+        write_media(site.path(), "sketch.jpg", "jpg");
+        fs::remove_file(site.path().join("media/b.gif")).unwrap();
+        let (library, _) = update(site.path());
+        assert_eq!(slug_of(&library, "sketch.png"), "sketch");
+        assert_eq!(slug_of(&library, "sketch.jpg"), "sketch-2");
+        assert_eq!(slug_of(&library, "b.png"), "b-2");
+
+        // A renamed file keeps its page
+        fs::rename(
+            site.path().join("media/sketch.png"),
+            site.path().join("media/final.png"),
+        )
+        .unwrap();
+        let (library, _) = update(site.path());
+        assert_eq!(slug_of(&library, "final.png"), "sketch");
+    }
+
+    /// Sets the date of `name` by hand, like `clutterlog edit` does.
+    fn set_date(site: &Path, name: &str, datetime: &str, pinned: bool) {
+        let mut library = MediaLibrary::new(site).unwrap();
+        let entry = library.entries.iter_mut().find(|e| e.name == name).unwrap();
+        entry.datetime = datetime.to_string();
+        entry.pinned = pinned;
+        library.save().unwrap();
+    }
+
+    fn counts(report: &UpdateReport) -> (usize, usize, usize) {
+        (report.added, report.renamed, report.removed)
+    }
+
+    #[test]
+    fn keeps_the_date_of_a_file_moved_into_an_album() {
+        let site = site_with(&[("sketch.png", "sketch"), ("other.png", "other")]);
+        update(site.path());
+        set_date(site.path(), "sketch.png", "2020-01-02T03:04:05", false);
+
+        fs::create_dir_all(site.path().join("media/robot arm")).unwrap();
+        fs::rename(
+            site.path().join("media/sketch.png"),
+            site.path().join("media/robot arm/sketch.png"),
+        )
+        .unwrap();
+        let (library, report) = update(site.path());
+        assert_eq!(counts(&report), (0, 1, 0));
+        assert!(library.get("sketch.png").is_none());
+        let moved = library.get("robot arm/sketch.png").unwrap();
+        assert_eq!(moved.datetime, "2020-01-02T03:04:05");
+        assert_eq!(moved.slug, "sketch");
+    }
+
+    #[test]
+    fn keeps_pinned_entries_and_moves_them_with_their_file() {
+        let site = site_with(&[("sketch.png", "sketch"), ("gone.png", "gone")]);
+        update(site.path());
+        set_date(site.path(), "sketch.png", "2020-01-02T03:04:05", true);
+        set_date(site.path(), "gone.png", "2021-01-01T00:00:00", true);
+
+        // A pinned entry waits for its file to come back
+        fs::remove_file(site.path().join("media/gone.png")).unwrap();
+        let (library, report) = update(site.path());
+        assert_eq!(counts(&report), (0, 0, 0));
+        assert_eq!(
+            library.get("gone.png").unwrap().datetime,
+            "2021-01-01T00:00:00"
+        );
+
+        fs::rename(
+            site.path().join("media/sketch.png"),
+            site.path().join("media/final.png"),
+        )
+        .unwrap();
+        let (library, report) = update(site.path());
+        assert_eq!(counts(&report), (0, 1, 0));
+        assert!(library.get("sketch.png").is_none());
+        let moved = library.get("final.png").unwrap();
+        assert!(moved.pinned);
+        assert_eq!(moved.datetime, "2020-01-02T03:04:05");
+    }
+
+    #[test]
+    fn does_not_take_identical_files_for_renames() {
+        // Both new at once
+        let site = site_with(&[("a.png", "same"), ("b.png", "same")]);
+        let (library, report) = update(site.path());
+        assert_eq!(counts(&report), (2, 0, 0));
+        assert_eq!(library.entries.len(), 2);
+
+        // A copy next to a file that's still there
+        write_media(site.path(), "c.png", "same");
+        let (library, report) = update(site.path());
+        assert_eq!(counts(&report), (1, 0, 0));
+        let names: Vec<&str> = library.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.png", "b.png", "c.png"]);
+    }
+
+    fn entry(name: &str, datetime: &str) -> MetaMedia {
+        MetaMedia {
+            name: name.to_string(),
+            slug: String::new(),
+            datetime: datetime.to_string(),
+            timezone: None,
+            hash: String::new(),
+            date_source: None,
+            tags: Vec::new(),
+            title: None,
+            description: None,
+            pinned: false,
+            hidden: false,
+            draft: false,
+            publish_after: None,
+        }
+    }
+
+    #[test]
+    fn selects_by_name_and_date() {
+        let date = |text: &str| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S").unwrap();
+        let june = entry("arm/first.jpg", "2024-06-10T12:00:00");
+        let july = entry("arm/second.jpg", "2024-07-01T00:00:00");
+        let broken = entry("arm/broken.jpg", "sometime");
+
+        assert!(Selection::default().is_empty());
+
+        let by_name = Selection {
+            patterns: vec!["arm/first.jpg".to_string(), "other/*".to_string()],
+            ..Selection::default()
+        };
+        assert!(by_name.matches(&june));
+        assert!(!by_name.matches(&july));
+
+        let by_range = Selection {
+            from: Some(date("2024-06-01T00:00:00")),
+            to: Some(date("2024-06-30T23:59:59")),
+            ..Selection::default()
+        };
+        assert!(by_range.matches(&june));
+        assert!(!by_range.matches(&july));
+        assert!(!by_range.matches(&broken));
+
+        let open_ended = Selection {
+            from: Some(date("2024-07-01T00:00:00")),
+            ..Selection::default()
+        };
+        assert!(!open_ended.matches(&june));
+        assert!(open_ended.matches(&july));
+
+        let both = Selection {
+            patterns: vec!["arm/*".to_string()],
+            to: Some(date("2024-06-30T23:59:59")),
+            ..Selection::default()
+        };
+        assert!(both.matches(&june));
+        assert!(!both.matches(&july));
+        assert!(!both.matches(&broken));
+    }
+}
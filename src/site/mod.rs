@@ -1,8 +1,25 @@
+mod album;
+mod archive;
+mod build_cache;
+mod build_manifest;
+mod feed;
+mod import;
+mod live_reload;
+mod media_date;
 mod media_library;
+mod pagination;
+mod preview;
+mod relative_url;
 pub mod server;
+mod tag;
+mod templates;
+mod url_path;
 mod website;
 mod website_info;
 mod website_media;
 
-pub use media_library::MediaLibrary;
-pub use website::Website;
+pub use import::{ImportOptions, import_media};
+pub use media_date::{parse_datetime_arg, parse_offset, parse_range_end, parse_shift};
+pub use media_library::{MediaLibrary, MetaEdit, Selection};
+pub use preview::PreviewStore;
+pub use website::{BuildTarget, PublishedMedia, Rebuild, Website};
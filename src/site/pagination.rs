@@ -0,0 +1,36 @@
+use serde::Serialize;
+
+use super::archive::Section;
+use super::website_media::MediaItem;
+
+/// Position of a gallery page among its siblings, for the links shown when
+/// JavaScript is off.
+#[derive(Serialize)]
+pub struct Pagination {
+    pub page: usize,
+    pub pages: usize,
+    pub prev_url: Option<String>,
+    pub next_url: Option<String>,
+}
+
+/// A gallery page's items, written as its `data.json`. `next` points to the
+/// following page's chunk, so the script can keep loading as the grid scrolls,
+/// and `sections` holds the months the items fall in, for date separators.
+#[derive(Serialize)]
+pub struct GalleryChunk<'a> {
+    pub page: usize,
+    pub pages: usize,
+    pub next: Option<String>,
+    pub sections: Vec<Section<'a>>,
+    pub items: Vec<&'a MediaItem>,
+}
+
+/// Directory of page `number` of the gallery in `gallery_dir` (empty or ending in
+/// `/`). The first page is the gallery itself.
+pub fn page_dir(gallery_dir: &str, number: usize) -> String {
+    if number <= 1 {
+        gallery_dir.to_string()
+    } else {
+        format!("{}page/{}/", gallery_dir, number)
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use super::media_library::CLUTTERLOG_DIR;
+
+/// Where preview builds publish media, since only the rendered files are kept
+/// in memory.
+const PREVIEW_DIR: &str = "preview";
+
+/// The pages, JSON and feeds of a preview build, by `/`-separated path relative
+/// to the site root. Cheap to clone, every clone holds the same files, so the
+/// server sees each rebuild as soon as it writes them.
+#[derive(Clone, Default)]
+pub struct PreviewStore {
+    files: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl PreviewStore {
+    pub fn get(&self, relative: &str) -> Option<String> {
+        let files = self.files.read().unwrap_or_else(|e| e.into_inner());
+        files.get(relative).cloned()
+    }
+
+    pub fn contains(&self, relative: &str) -> bool {
+        let files = self.files.read().unwrap_or_else(|e| e.into_inner());
+        files.contains_key(relative)
+    }
+
+    /// Stores `content` at `relative`. Returns `false` if it already held it.
+    pub fn insert(&self, relative: &str, content: &str) -> bool {
+        let mut files = self.files.write().unwrap_or_else(|e| e.into_inner());
+        if files
+            .get(relative)
+            .is_some_and(|existing| existing == content)
+        {
+            return false;
+        }
+        files.insert(relative.to_string(), content.to_string());
+        true
+    }
+
+    /// Drops every file `keep` refuses, returning their paths.
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut files = self.files.write().unwrap_or_else(|e| e.into_inner());
+        let removed: Vec<String> = files.keys().filter(|f| !keep(f)).cloned().collect();
+        for file in &removed {
+            files.remove(file);
+        }
+        removed
+    }
+}
+
+pub fn preview_dir(site_path: &Path) -> PathBuf {
+    site_path.join(CLUTTERLOG_DIR).join(PREVIEW_DIR)
+}
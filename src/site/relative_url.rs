@@ -0,0 +1,139 @@
+use serde_json::Value;
+
+/// Rewrites the URLs inside the site at `base_url` that `text` holds (a single
+/// URL or a `srcset` list) relative to the page whose way back to the site root
+/// is `root`, e.g. `../../`. Text that doesn't start with such a URL is returned
+/// as it is.
+pub fn relative_url(text: &str, base_url: &str, root: &str) -> String {
+    let prefix = format!("{}/", base_url);
+    if !text.starts_with(&prefix) {
+        return text.to_string();
+    }
+    // The site root itself, linked from a page at the root
+    let root = if root.is_empty() && text == prefix {
+        "./"
+    } else {
+        root
+    };
+    text.replace(&prefix, root)
+}
+
+/// Fields of the JSON the site writes (gallery chunks, items, image sources and
+/// archive sections) that hold site URLs.
+const URL_FIELDS: &[&str] = &[
+    "url",
+    "image_url",
+    "original_url",
+    "thumb_url",
+    "srcset",
+    "next",
+];
+
+/// Applies `relative_url` to the URL fields of a JSON document. Other strings, like
+/// titles and descriptions, are left alone even when they start with a site URL.
+pub fn relative_json(value: &mut Value, base_url: &str, root: &str) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                relative_json(value, base_url, root);
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(text) if URL_FIELDS.contains(&key.as_str()) => {
+                        *text = relative_url(text, base_url, root)
+                    }
+                    _ => relative_json(value, base_url, root),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const BASE: &str = "https://example.com/log";
+
+    #[test]
+    fn makes_site_urls_relative() {
+        let url = |text| relative_url(text, BASE, "../../");
+        assert_eq!(
+            url("https://example.com/log/media/a.png"),
+            "../../media/a.png"
+        );
+        assert_eq!(url("https://example.com/log/"), "../../");
+        assert_eq!(
+            url("https://example.com/log/m/a_800.png 800w, https://example.com/log/m/a.png 1600w"),
+            "../../m/a_800.png 800w, ../../m/a.png 1600w"
+        );
+        // Outside the site, or only sharing a prefix with it
+        assert_eq!(
+            url("https://example.org/a.png"),
+            "https://example.org/a.png"
+        );
+        assert_eq!(
+            url("https://example.com/logbook/"),
+            "https://example.com/logbook/"
+        );
+        assert_eq!(url("#media=a.png"), "#media=a.png");
+    }
+
+    #[test]
+    fn links_the_root_from_the_root() {
+        assert_eq!(relative_url("https://example.com/log/", BASE, ""), "./");
+        assert_eq!(
+            relative_url("https://example.com/log/tags/", BASE, ""),
+            "tags/"
+        );
+    }
+
+    #[test]
+    fn rewrites_json_url_fields() {
+        let mut value = json!({
+            "url": "https://example.com/log/m/a/",
+            "count": 2,
+            "next": "https://example.com/log/page/2/data.json",
+            "items": [
+                {
+                    "thumb_url": "https://example.com/log/media/a_thumb.webp",
+                    "sources": [{
+                        "mime_type": "image/webp",
+                        "srcset": "https://example.com/log/media/a_800.webp 800w",
+                    }],
+                    "title": "https://example.com/log/ turns one",
+                    "description": "https://example.com/log/m/a/",
+                    "tags": ["https://example.com/log/"],
+                },
+                null,
+            ],
+        });
+        relative_json(&mut value, BASE, "../");
+        assert_eq!(
+            value,
+            json!({
+                "url": "../m/a/",
+                "count": 2,
+                "next": "../page/2/data.json",
+                "items": [
+                    {
+                        "thumb_url": "../media/a_thumb.webp",
+                        "sources": [{
+                            "mime_type": "image/webp",
+                            "srcset": "../media/a_800.webp 800w",
+                        }],
+                        "title": "https://example.com/log/ turns one",
+                        "description": "https://example.com/log/m/a/",
+                        "tags": ["https://example.com/log/"],
+                    },
+                    null,
+                ],
+            })
+        );
+    }
+}
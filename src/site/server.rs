@@ -1,56 +1,258 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
 
-use actix_files::Files;
-use actix_web::{App, HttpServer};
-use notify::{RecursiveMode, Watcher};
+use actix_files::{NamedFile, file_extension_to_mime};
+use actix_web::http::header;
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc::unbounded_channel;
 
-use super::website::Website;
+use super::live_reload::{CLIENT_JS, CLIENT_PATH, EVENTS_PATH, LiveReload, inject_client};
+use super::media_library::{CLUTTERLOG_DIR, METAMEDIA_TOML};
+use super::preview::PreviewStore;
+use super::templates::TEMPLATES_DIR;
+use super::website::{BuildTarget, DEFAULT_MEDIA_DIR, PublishedMedia, Rebuild, Website};
+use super::website_info::SITE_TOML;
+use super::website_media::is_supported_media;
 
-pub fn serve(
+/// How many ports after the requested one are tried when it's taken.
+const PORT_ATTEMPTS: u16 = 10;
+
+/// How `serve` serves the site.
+pub struct ServeOptions {
+    /// Rebuild the site as it changes, and reload the pages showing it.
+    pub watch: bool,
+    /// Passed on to the rebuilds.
+    pub base_url_override: Option<String>,
+    pub drafts: bool,
+}
+
+/// What the request handlers share.
+struct ServerState {
+    /// Where the build put its files, or its media for previews.
     build_dir: PathBuf,
-    port: u16,
-    watch: bool,
+    /// Set for previews, whose pages are served from memory.
+    store: Option<PreviewStore>,
+    /// Set in watch mode, where served pages get the live reload client.
+    live_reload: Option<LiveReload>,
+}
+
+/// Serves the site at `site_path`, already built into `target`, on `listener`
+/// until Ctrl-C. With `watch`, the site is rebuilt as it changes, starting from
+/// the media that build `published`. Returns once both the server and the
+/// watcher have stopped.
+pub fn serve(
+    listener: TcpListener,
     site_path: PathBuf,
-    base_url_override: Option<String>,
-) -> std::io::Result<()> {
-    let addr = format!("127.0.0.1:{}", port);
-    println!("Serving at http://{}", addr);
-    println!("Build directory: {}", build_dir.display());
+    target: BuildTarget,
+    published: PublishedMedia,
+    options: ServeOptions,
+) -> io::Result<()> {
+    let ServeOptions {
+        watch,
+        base_url_override,
+        drafts,
+    } = options;
+    let build_dir = target.dir(&site_path);
+
+    println!("Serving at http://{}", listener.local_addr()?);
+    match target {
+        BuildTarget::Deploy => println!("Build directory: {}", build_dir.display()),
+        BuildTarget::Preview(_) => println!("Previewing from memory, build directory untouched"),
+    }
 
     let shutdown = Arc::new(AtomicBool::new(false));
+    let live_reload = watch.then(LiveReload::default);
 
-    if watch {
+    let watcher = live_reload.clone().map(|live_reload| {
         println!("Watching for changes...");
         let sp = site_path.clone();
         let url = base_url_override.clone();
+        let target = target.clone();
         let shutdown_w = shutdown.clone();
         thread::spawn(move || {
-            watch_loop(sp, url, shutdown_w);
-        });
-    }
+            watch_loop(sp, url, drafts, target, published, live_reload, shutdown_w);
+        })
+    });
 
-    let shutdown_s = shutdown.clone();
+    // Actix's own signal handling only stops the server, the handler stops both
+    let (stop_tx, mut stop_rx) = unbounded_channel();
     ctrlc::set_handler(move || {
-        shutdown_s.store(true, Ordering::SeqCst);
+        let _ = stop_tx.send(());
     })
-    .expect("Error setting Ctrl-C handler");
+    .map_err(io::Error::other)?;
 
-    let build_dir_server = build_dir.clone();
-    let server = HttpServer::new(move || {
-        App::new().service(Files::new("/", build_dir_server.clone()).index_file("index.html"))
-    })
-    .bind(&addr)?
-    .run();
+    let state = web::Data::new(ServerState {
+        build_dir,
+        store: target.store(),
+        live_reload: live_reload.clone(),
+    });
+    let result = actix_web::rt::System::new().block_on(async move {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .route(EVENTS_PATH, web::get().to(live_reload_events))
+                .route(CLIENT_PATH, web::get().to(live_reload_client))
+                .route("/{path:.*}", web::get().to(serve_file))
+        })
+        .disable_signals()
+        .listen(listener)?
+        .run();
+
+        let handle = server.handle();
+        actix_web::rt::spawn(async move {
+            if stop_rx.recv().await.is_some() {
+                println!("Shutting down server...");
+                // Event streams stay open otherwise, holding up the shutdown
+                if let Some(live_reload) = live_reload {
+                    live_reload.close();
+                }
+                handle.stop(true).await;
+            }
+        });
+
+        server.await
+    });
 
-    actix_web::rt::System::new().block_on(server)
+    shutdown.store(true, Ordering::SeqCst);
+    if let Some(watcher) = watcher {
+        let _ = watcher.join();
+    }
+    result
 }
 
-fn watch_loop(site_path: PathBuf, base_url_override: Option<String>, shutdown: Arc<AtomicBool>) {
+/// Binds `port` on `host`, or the first free port among the next `PORT_ATTEMPTS`.
+pub fn bind(host: &str, port: u16) -> io::Result<TcpListener> {
+    let last = port.saturating_add(PORT_ATTEMPTS);
+    let mut port = port;
+    loop {
+        match TcpListener::bind((host, port)) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && port < last => {
+                println!("Port {} is in use, trying {}", port, port + 1);
+                port += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The site URL given with `-b`, moved to `actual` if it named the `requested`
+/// port that `bind` had to skip, so absolute URLs point at the running server.
+pub fn follow_port(url: &str, requested: u16, actual: u16) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    match authority.strip_suffix(&format!(":{}", requested)) {
+        Some(host) if requested != actual => format!("{}://{}:{}{}", scheme, host, actual, path),
+        _ => url.to_string(),
+    }
+}
+
+/// Serves a file from the preview store or the build directory, or a directory's
+/// `index.html`. In watch mode, HTML pages get the live reload client.
+async fn serve_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<ServerState>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(relative) = safe_relative_path(&path) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let mut name = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut file = state.build_dir.join(relative);
+
+    let index = if name.is_empty() {
+        "index.html".to_string()
+    } else {
+        format!("{}/index.html", name)
+    };
+    let stored_dir = state.store.as_ref().is_some_and(|s| s.contains(&index));
+    if stored_dir || file.is_dir() {
+        // Relative links on the page only resolve from the directory itself
+        if !req.path().ends_with('/') {
+            let location = match req.query_string() {
+                "" => format!("{}/", req.path()),
+                query => format!("{}/?{}", req.path(), query),
+            };
+            return Ok(HttpResponse::MovedPermanently()
+                .insert_header((header::LOCATION, location))
+                .finish());
+        }
+        name = index;
+        file = file.join("index.html");
+    }
+
+    let is_html = file.extension().is_some_and(|ext| ext == "html");
+    let content = match state.store.as_ref().and_then(|s| s.get(&name)) {
+        Some(content) => content,
+        None if is_html && state.live_reload.is_some() => match fs::read_to_string(&file) {
+            Ok(html) => html,
+            Err(_) => return Ok(HttpResponse::NotFound().finish()),
+        },
+        None => return Ok(NamedFile::open_async(&file).await?.into_response(&req)),
+    };
+
+    let content = match &state.live_reload {
+        Some(_) if is_html => inject_client(&content),
+        _ => content,
+    };
+    // Whatever is read into a string is UTF-8
+    let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let mime = file_extension_to_mime(extension);
+    Ok(HttpResponse::Ok()
+        .content_type(format!("{}; charset=utf-8", mime))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .body(content))
+}
+
+/// Resolves a request path inside the build directory. Refuses anything that
+/// would leave it, and dotfiles like the build manifest.
+fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(name) if !name.to_string_lossy().starts_with('.')))
+        .then(|| relative.to_path_buf())
+}
+
+async fn live_reload_events(state: web::Data<ServerState>) -> HttpResponse {
+    match &state.live_reload {
+        Some(live_reload) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .body(live_reload.subscribe()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn live_reload_client() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/javascript; charset=utf-8")
+        .body(CLIENT_JS)
+}
+
+fn watch_loop(
+    site_path: PathBuf,
+    base_url_override: Option<String>,
+    drafts: bool,
+    target: BuildTarget,
+    mut published: PublishedMedia,
+    live_reload: LiveReload,
+    shutdown: Arc<AtomicBool>,
+) {
     let (tx, rx) = channel();
 
     let mut watcher = match notify::recommended_watcher(tx) {
@@ -61,21 +263,28 @@ fn watch_loop(site_path: PathBuf, base_url_override: Option<String>, shutdown: A
         }
     };
 
-    let media_path = site_path.join("media");
-    let site_toml = site_path.join("site.toml");
+    // Events carry absolute paths, compared against these
+    let site_path = fs::canonicalize(&site_path).unwrap_or(site_path);
+    let paths = WatchedPaths::new(&site_path);
 
-    if media_path.exists()
-        && let Err(e) = watcher.watch(&media_path, RecursiveMode::Recursive)
-    {
-        eprintln!("Failed to watch media directory: {}", e);
-    }
-
-    if site_toml.exists()
-        && let Err(e) = watcher.watch(&site_toml, RecursiveMode::NonRecursive)
-    {
-        eprintln!("Failed to watch site.toml: {}", e);
+    // The site directory itself tells about site.toml and a templates directory
+    // being created, `.clutterlog` about metamedia.toml
+    let watches = [
+        (&paths.media, RecursiveMode::Recursive),
+        (&paths.templates, RecursiveMode::Recursive),
+        (&site_path, RecursiveMode::NonRecursive),
+        (&paths.clutterlog, RecursiveMode::NonRecursive),
+    ];
+    for (path, mode) in watches {
+        if path.exists()
+            && let Err(e) = watcher.watch(path, mode)
+        {
+            eprintln!("Failed to watch {}: {}", path.display(), e);
+        }
     }
 
+    // `published` holds what the previous build published, so media that didn't
+    // change is reused
     let debounce_duration = Duration::from_millis(300);
 
     loop {
@@ -84,21 +293,194 @@ fn watch_loop(site_path: PathBuf, base_url_override: Option<String>, shutdown: A
             break;
         }
 
-        if rx.recv_timeout(debounce_duration).is_ok() {
-            while rx.recv_timeout(Duration::from_millis(50)).is_ok() {}
-
-            if shutdown.load(Ordering::SeqCst) {
-                break;
+        let Some(mut rebuild) = rx
+            .recv_timeout(debounce_duration)
+            .ok()
+            .and_then(|event| paths.classify(event))
+        else {
+            continue;
+        };
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(50)) {
+            if let Some(more) = paths.classify(event) {
+                rebuild = rebuild.merge(more);
             }
+        }
+
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        // A templates directory created since the watch started
+        if paths.templates.exists()
+            && let Err(e) = watcher.watch(&paths.templates, RecursiveMode::Recursive)
+        {
+            eprintln!("Failed to watch {}: {}", paths.templates.display(), e);
+        }
 
-            println!("Change detected, rebuilding...");
-            match Website::load(&site_path) {
-                Ok(website) => match website.build(base_url_override.as_deref()) {
-                    Ok(report) => println!("Rebuilt successfully\n{}", report),
-                    Err(e) => eprintln!("Error rebuilding: {}", e),
-                },
-                Err(e) => eprintln!("Error loading site: {}", e),
+        match &rebuild {
+            Rebuild::Pages => println!("Change detected, rendering pages..."),
+            Rebuild::Media(names) => println!(
+                "Change detected, processing {} and rendering pages...",
+                names.iter().cloned().collect::<Vec<_>>().join(", ")
+            ),
+            Rebuild::Full => println!("Change detected, rebuilding..."),
+        }
+        let result = Website::load(&site_path).and_then(|website| {
+            let website = website.served();
+            website.rebuild(
+                base_url_override.as_deref(),
+                drafts,
+                &target,
+                &rebuild,
+                &mut published,
+            )
+        });
+        match result {
+            Ok(report) => {
+                println!("Rebuilt successfully\n{}", report);
+                live_reload.build_succeeded(&report.changed_files);
+            }
+            Err(e) => {
+                eprintln!("Error rebuilding: {}", e);
+                live_reload.build_failed(e.to_string());
+                // Whatever it had published may be gone or half done
+                published = PublishedMedia::default();
             }
         }
     }
 }
+
+/// The parts of the site the watcher tells apart.
+struct WatchedPaths {
+    site_toml: PathBuf,
+    media: PathBuf,
+    templates: PathBuf,
+    clutterlog: PathBuf,
+    metamedia: PathBuf,
+}
+
+impl WatchedPaths {
+    fn new(site_path: &Path) -> Self {
+        let clutterlog = site_path.join(CLUTTERLOG_DIR);
+        Self {
+            site_toml: site_path.join(SITE_TOML),
+            media: site_path.join(DEFAULT_MEDIA_DIR),
+            templates: site_path.join(TEMPLATES_DIR),
+            metamedia: clutterlog.join(METAMEDIA_TOML),
+            clutterlog,
+        }
+    }
+
+    /// What a watcher event requires rebuilding, if anything. Reading files is
+    /// reported too, and since every build reads the site, it must not trigger
+    /// another one.
+    fn classify(&self, event: notify::Result<notify::Event>) -> Option<Rebuild> {
+        let event = event.ok()?;
+        if matches!(event.kind, EventKind::Access(_)) {
+            return None;
+        }
+        event
+            .paths
+            .iter()
+            .filter_map(|path| self.classify_path(path))
+            .reduce(Rebuild::merge)
+    }
+
+    fn classify_path(&self, path: &Path) -> Option<Rebuild> {
+        if path == self.site_toml {
+            return Some(Rebuild::Full);
+        }
+        if path == self.metamedia || path.starts_with(&self.templates) {
+            return Some(Rebuild::Pages);
+        }
+        let relative = path.strip_prefix(&self.media).ok()?;
+        let hidden = relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        if hidden {
+            return None;
+        }
+        // Album names may have dots (`v1.2`), so directories are told apart by
+        // looking at the disk, not at the name
+        if path.is_dir() {
+            return Some(Rebuild::Full);
+        }
+        if is_supported_media(path) {
+            let name = relative.to_string_lossy().replace('\\', "/");
+            return Some(Rebuild::Media(BTreeSet::from([name])));
+        }
+        if path.extension().is_some_and(|ext| ext == "txt") {
+            // Sidecars only feed the pages
+            Some(Rebuild::Pages)
+        } else if path.exists() {
+            // Any other file isn't part of the site
+            None
+        } else {
+            // Gone: possibly a directory moved or removed, with whatever media it held
+            Some(Rebuild::Full)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe(rebuild: Option<Rebuild>) -> String {
+        match rebuild {
+            None => "none".to_string(),
+            Some(Rebuild::Pages) => "pages".to_string(),
+            Some(Rebuild::Media(names)) => format!("media {:?}", names),
+            Some(Rebuild::Full) => "full".to_string(),
+        }
+    }
+
+    #[test]
+    fn classifies_changed_paths() {
+        let site = tempfile::tempdir().unwrap();
+        let watched = WatchedPaths::new(site.path());
+        let media = site.path().join(DEFAULT_MEDIA_DIR);
+        fs::create_dir_all(media.join("v1.2")).unwrap();
+        fs::create_dir_all(media.join(".git")).unwrap();
+        fs::write(media.join("notes.md"), "").unwrap();
+        let classify = |path: PathBuf| describe(watched.classify_path(&path));
+
+        assert_eq!(classify(site.path().join(SITE_TOML)), "full");
+        assert_eq!(classify(watched.metamedia.clone()), "pages");
+        assert_eq!(
+            classify(site.path().join(TEMPLATES_DIR).join("a.html")),
+            "pages"
+        );
+        assert_eq!(classify(media.join("v1.2")), "full");
+        assert_eq!(classify(media.join("gone.d")), "full");
+        assert_eq!(classify(media.join("robot")), "full");
+        assert_eq!(
+            classify(media.join("v1.2/a.png")),
+            r#"media {"v1.2/a.png"}"#
+        );
+        assert_eq!(classify(media.join("a.txt")), "pages");
+        assert_eq!(classify(media.join("notes.md")), "none");
+        assert_eq!(classify(media.join(".git/index")), "none");
+        assert_eq!(classify(site.path().join("build/index.html")), "none");
+    }
+
+    #[test]
+    fn follows_the_port_bind_fell_back_to() {
+        let follow = |url| follow_port(url, 8088, 8089);
+        assert_eq!(follow("http://127.0.0.1:8088"), "http://127.0.0.1:8089");
+        assert_eq!(
+            follow("http://localhost:8088/log/"),
+            "http://localhost:8089/log/"
+        );
+        assert_eq!(follow("http://localhost:80880"), "http://localhost:80880");
+        assert_eq!(
+            follow("https://example.com/8088"),
+            "https://example.com/8088"
+        );
+        assert_eq!(follow("example.com:8088"), "example.com:8088");
+        assert_eq!(
+            follow_port("http://127.0.0.1:8088", 8088, 8088),
+            "http://127.0.0.1:8088"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::url_path::encode_path;
+use super::website_media::slugify;
+
+/// A tag attached to one or more items, rendered as its own page and feed.
+#[derive(Serialize, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub slug: String,
+    pub url: String,
+    pub count: usize,
+}
+
+impl Tag {
+    pub fn matches(&self, name: &str) -> bool {
+        slugify(name) == self.slug
+    }
+}
+
+/// Groups tag names (one per tagged item) into tags sorted by slug. Names that
+/// slugify to the same value are merged, keeping the first spelling seen.
+pub fn collect_tags<'a>(
+    names: impl Iterator<Item = &'a str>,
+    base_url: &str,
+    tags_dir: &str,
+) -> Vec<Tag> {
+    let mut grouped: BTreeMap<String, (&str, usize)> = BTreeMap::new();
+
+    for name in names {
+        grouped.entry(slugify(name)).or_insert((name, 0)).1 += 1;
+    }
+
+    grouped
+        .into_iter()
+        .map(|(slug, (name, count))| Tag {
+            name: name.to_string(),
+            url: format!("{}/{}/{}/", base_url, tags_dir, encode_path(&slug)),
+            slug,
+            count,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_tag_urls() {
+        let tags = collect_tags(["Größe", "größe", "Robot Arm"].into_iter(), "/log", "tags");
+        let urls: Vec<(&str, &str, usize)> = tags
+            .iter()
+            .map(|tag| (tag.slug.as_str(), tag.url.as_str(), tag.count))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                ("größe", "/log/tags/gr%C3%B6%C3%9Fe/", 2),
+                ("robot-arm", "/log/tags/robot-arm/", 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use minijinja::{AutoEscape, Environment, Error, ErrorKind, State, escape_formatter};
+use serde::Serialize;
+
+use super::relative_url::relative_url;
+use super::website::WebsiteError;
+
+pub const TEMPLATES_DIR: &str = "templates";
+
+/// Built-in templates, used whenever the site doesn't provide its own version.
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    ("index.html", include_str!("../../template/index.html")),
+    ("album.html", include_str!("../../template/album.html")),
+    ("item.html", include_str!("../../template/item.html")),
+    ("rss.xml", include_str!("../../template/rss.xml")),
+    ("atom.xml", include_str!("../../template/atom.xml")),
+    ("sitemap.xml", include_str!("../../template/sitemap.xml")),
+    ("robots.txt", include_str!("../../template/robots.txt")),
+    ("tag.html", include_str!("../../template/tag.html")),
+    ("archive.html", include_str!("../../template/archive.html")),
+    (
+        "partials/head.html",
+        include_str!("../../template/partials/head.html"),
+    ),
+    (
+        "partials/footer.html",
+        include_str!("../../template/partials/footer.html"),
+    ),
+    (
+        "partials/albums.html",
+        include_str!("../../template/partials/albums.html"),
+    ),
+    (
+        "partials/tags.html",
+        include_str!("../../template/partials/tags.html"),
+    ),
+    (
+        "partials/archive.html",
+        include_str!("../../template/partials/archive.html"),
+    ),
+    (
+        "partials/grid.html",
+        include_str!("../../template/partials/grid.html"),
+    ),
+    (
+        "partials/lightbox.html",
+        include_str!("../../template/partials/lightbox.html"),
+    ),
+];
+
+/// Built-in static assets, copied verbatim into the build (not rendered).
+pub const DEFAULT_ASSETS: &[(&str, &str)] = &[
+    (
+        "public/style.css",
+        include_str!("../../template/public/style.css"),
+    ),
+    (
+        "public/clutterlog.js",
+        include_str!("../../template/public/clutterlog.js"),
+    ),
+];
+
+/// Template lookup for a site. Files in the site's `templates/` directory take
+/// precedence over the embedded defaults, so a site can override a single
+/// partial without having to copy the whole template set.
+pub struct Templates {
+    env: Environment<'static>,
+    dir: PathBuf,
+}
+
+impl Templates {
+    pub fn new(site_path: &Path) -> Self {
+        let dir = site_path.join(TEMPLATES_DIR);
+
+        let mut env = Environment::new();
+        env.set_keep_trailing_newline(true);
+
+        // MiniJinja's HTML escaping also rewrites `/`, which makes every URL in
+        // the output unreadable. Escape only what markup actually requires.
+        env.set_formatter(|out, state, value| {
+            if state.auto_escape() == AutoEscape::Html && !value.is_safe() {
+                if !value.is_undefined() {
+                    out.write_str(&escape_markup(&value.to_string()))?;
+                }
+                return Ok(());
+            }
+            escape_formatter(out, state, value)
+        });
+
+        env.add_filter("relative", relative);
+
+        let loader_dir = dir.clone();
+        env.set_loader(move |name| {
+            if let Some(path) = override_path(&loader_dir, name)
+                && path.is_file()
+            {
+                return fs::read_to_string(&path).map(Some).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidOperation,
+                        format!("failed to read template '{}'", path.display()),
+                    )
+                    .with_source(e)
+                });
+            }
+
+            Ok(DEFAULT_TEMPLATES
+                .iter()
+                .find(|(default_name, _)| *default_name == name)
+                .map(|(_, content)| content.to_string()))
+        });
+
+        Self { env, dir }
+    }
+
+    pub fn render<S: Serialize>(&self, name: &str, context: S) -> Result<String, WebsiteError> {
+        self.env
+            .get_template(name)
+            .and_then(|template| template.render(context))
+            .map_err(|e| WebsiteError::Template(Box::new(e)))
+    }
+
+    /// Returns the contents of a static asset, preferring the site-local override.
+    pub fn asset(&self, name: &str) -> Result<Cow<'static, str>, WebsiteError> {
+        if let Some(path) = override_path(&self.dir, name)
+            && path.is_file()
+        {
+            return fs::read_to_string(&path)
+                .map(Cow::Owned)
+                .map_err(|e| WebsiteError::Io(path, e));
+        }
+
+        DEFAULT_ASSETS
+            .iter()
+            .find(|(default_name, _)| *default_name == name)
+            .map(|(_, content)| Cow::Borrowed(*content))
+            .ok_or_else(|| WebsiteError::MissingAsset(name.to_string()))
+    }
+}
+
+/// The `relative` filter: with `relative_urls` on, makes a URL inside the site
+/// relative to the page being rendered, using its `base_url` and `root`.
+fn relative(state: &State, url: String) -> String {
+    let enabled = state
+        .lookup("site")
+        .and_then(|site| site.get_attr("relative_urls").ok())
+        .is_some_and(|value| value.is_true());
+    let base_url = state.lookup("base_url");
+    let root = state.lookup("root");
+    match (
+        base_url.as_ref().and_then(|v| v.as_str()),
+        root.as_ref().and_then(|v| v.as_str()),
+    ) {
+        (Some(base_url), Some(root)) if enabled => relative_url(&url, base_url, root),
+        _ => url,
+    }
+}
+
+/// Resolves a template name inside the override directory, refusing names that
+/// would escape it.
+fn override_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let relative = Path::new(name);
+    if relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        Some(dir.join(relative))
+    } else {
+        None
+    }
+}
+
+/// Escaping that is valid in both HTML and XML text and attribute values.
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -1,38 +1,68 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
+use minijinja::context;
 use rayon::prelude::*;
+use serde::Serialize;
 
-use super::media_library::{MediaLibrary, MediaLibraryError};
+use super::album::{Album, collect_albums};
+use super::archive::{Period, collect_archive, sections};
+use super::build_cache::{BuildCache, SourceHash, cache_dir};
+use super::build_manifest::{BuildManifest, manifest_path};
+use super::feed::JsonFeed;
+use super::media_library::{MediaLibrary, MediaLibraryError, MetaMedia, unique_slug};
+use super::pagination::{GalleryChunk, Pagination, page_dir};
+use super::preview::{PreviewStore, preview_dir};
+use super::relative_url::relative_json;
+use super::tag::{Tag, collect_tags};
+use super::templates::{DEFAULT_ASSETS, Templates};
+use super::url_path::encode_path;
 use super::website_info::{SITE_TOML, WebsiteInfo, WebsiteInfoError};
-use super::website_media::{GenerationResult, WebsiteMedia};
+use super::website_media::{
+    FeedItem, GenerationResult, ItemPage, MediaItem, WebsiteMedia, scan_media_files,
+};
 
 const DEFAULT_BUILD_DIR: &str = "build";
-const DEFAULT_MEDIA_DIR: &str = "media";
+pub(super) const DEFAULT_MEDIA_DIR: &str = "media";
 const DEFAULT_PUBLIC_DIR: &str = "public";
+const DEFAULT_ALBUMS_DIR: &str = "albums";
+const DEFAULT_PAGES_DIR: &str = "m";
+const DEFAULT_TAGS_DIR: &str = "tags";
+const DEFAULT_ARCHIVE_DIR: &str = "archive";
 
 const DEFAULT_FEED_FILE: &str = "feed.xml";
+const ATOM_FEED_FILE: &str = "atom.xml";
+const JSON_FEED_FILE: &str = "feed.json";
+const SITEMAP_FILE: &str = "sitemap.xml";
+const ROBOTS_FILE: &str = "robots.txt";
+/// Items of a gallery page, fetched by `clutterlog.js`.
+const GALLERY_DATA_FILE: &str = "data.json";
 
-const TEMPLATE_INDEX: &str = include_str!("../../template/index.html");
-const TEMPLATE_STYLE: &str = include_str!("../../template/public/style.css");
-const TEMPLATE_JS: &str = include_str!("../../template/public/clutterlog.js");
 const TEMPLATE_GITHUB_ACTION: &str = include_str!("../../template/github_action.yaml");
-const TEMPLATE_RSS: &str = include_str!("../../template/rss.xml");
+const TEMPLATE_GITIGNORE: &str = include_str!("../../template/gitignore");
 
 pub struct BuildReport {
     pub items_processed: usize,
     pub items_skipped: usize,
     pub total_media_size: u64,
     pub total_thumbs_size: u64,
+    /// Stale outputs deleted from the build directory.
+    pub files_removed: usize,
+    /// Outputs written with new contents or removed, relative to the build
+    /// directory. Tells `server --watch` what browsers need to reload.
+    pub changed_files: Vec<String>,
     pub processing_time: Duration,
 }
 
 impl BuildReport {
     fn from_results(
-        results: Vec<GenerationResult>,
+        results: &[&GenerationResult],
         items_skipped: usize,
+        files_removed: usize,
+        changed_files: Vec<String>,
         processing_time: Duration,
     ) -> Self {
         let items_processed = results.len();
@@ -43,6 +73,8 @@ impl BuildReport {
             items_skipped,
             total_media_size,
             total_thumbs_size,
+            files_removed,
+            changed_files,
             processing_time,
         }
     }
@@ -63,6 +95,7 @@ impl std::fmt::Display for BuildReport {
             "  Total thumbs size: {}",
             format_size(self.total_thumbs_size)
         )?;
+        writeln!(f, "  Stale files removed: {}", self.files_removed)?;
         write!(
             f,
             "  Processing time: {}",
@@ -160,15 +193,51 @@ impl Website {
         fs::write(&deploy_path, TEMPLATE_GITHUB_ACTION)
             .map_err(|e| WebsiteError::Io(deploy_path, e))?;
 
+        write_gitignore(path)?;
+
         Ok(website)
     }
 
-    pub fn build(&self, base_url_override: Option<&str>) -> Result<BuildReport, WebsiteError> {
+    /// The site as `server` builds it: linked with URLs relative to each page, so
+    /// the pages work whichever address and port the server ends up on.
+    pub fn served(mut self) -> Self {
+        self.info.relative_urls = true;
+        self
+    }
+
+    /// Builds the site into `build/`. With `drafts`, hidden, draft and scheduled
+    /// items are included too, for previewing.
+    pub fn build(
+        &self,
+        base_url_override: Option<&str>,
+        drafts: bool,
+    ) -> Result<BuildReport, WebsiteError> {
+        self.rebuild(
+            base_url_override,
+            drafts,
+            &BuildTarget::Deploy,
+            &Rebuild::Full,
+            &mut PublishedMedia::default(),
+        )
+    }
+
+    /// Builds the site like `build`, into `target`, skipping the media that
+    /// `published` (kept from the previous build in the same process) says is
+    /// current, as far as `rebuild` tells. Updates `published` with this build's
+    /// media.
+    pub fn rebuild(
+        &self,
+        base_url_override: Option<&str>,
+        drafts: bool,
+        target: &BuildTarget,
+        rebuild: &Rebuild,
+        published: &mut PublishedMedia,
+    ) -> Result<BuildReport, WebsiteError> {
         let start = Instant::now();
         let base_url = base_url_override
             .unwrap_or(&self.info.url)
             .trim_end_matches('/');
-        let build_path = self.path.join(DEFAULT_BUILD_DIR);
+        let build_path = target.dir(&self.path);
         fs::create_dir_all(&build_path).map_err(|e| WebsiteError::Io(build_path.clone(), e))?;
 
         let build_media_path = build_path.join(DEFAULT_MEDIA_DIR);
@@ -178,126 +247,780 @@ impl Website {
         let public_path = build_path.join(DEFAULT_PUBLIC_DIR);
         fs::create_dir_all(&public_path).map_err(|e| WebsiteError::Io(public_path.clone(), e))?;
 
+        let manifest_path = target.manifest_path(&self.path);
+        let previous_manifest = BuildManifest::load(&manifest_path);
+        let mut manifest = BuildManifest::new(target.store());
+
         // Update media metadata before scanning
         let source_media_path = self.path.join(DEFAULT_MEDIA_DIR);
         let mut library = MediaLibrary::new(&self.path)?;
-        library.update_metadata(&source_media_path)?;
+        library.update_metadata(&source_media_path, self.info.timezone)?;
+
+        // Scan source media directory, publish derivatives from the cache (generating
+        // missing ones), and collect data entries
+        let cache = BuildCache::load(&self.path);
+        let output = MediaOutput {
+            dest_path: &build_media_path,
+            cache: &cache,
+            previous: &previous_manifest,
+            published,
+            rebuild,
+        };
+        let ScannedMedia {
+            mut entries,
+            published: scanned,
+            items_skipped,
+        } = self.scan_and_copy_media(&output, &library, base_url, drafts)?;
+        *published = scanned;
+        let generation_results: Vec<&GenerationResult> =
+            published.items.values().map(|item| &item.result).collect();
+
+        for (file, key) in generation_results.iter().flat_map(|r| &r.files) {
+            manifest.insert(
+                format!("{}/{}", DEFAULT_MEDIA_DIR, file),
+                key.clone(),
+                &previous_manifest,
+            );
+        }
+        let used: HashSet<PathBuf> = generation_results
+            .iter()
+            .flat_map(|r| r.cached.iter().cloned())
+            .collect();
+        let library_hashes: HashSet<&str> =
+            library.entries.iter().map(|e| e.hash.as_str()).collect();
+        cache.save(published.sources(), &used, &library_hashes)?;
+
+        // Galleries list the newest items first, and are paginated in that order
+        entries.sort_by_key(|e| std::cmp::Reverse(e.page.timestamp));
+
+        let albums = collect_albums(
+            entries.iter().filter_map(|e| {
+                e.page
+                    .album
+                    .as_deref()
+                    .map(|album| (album, e.page.timestamp, e.page.thumb_url.as_str()))
+            }),
+            base_url,
+            DEFAULT_ALBUMS_DIR,
+        );
+
+        let tags = collect_tags(
+            entries
+                .iter()
+                .flat_map(|e| e.page.tags.iter().map(|t| t.as_str())),
+            base_url,
+            DEFAULT_TAGS_DIR,
+        );
 
-        // Scan source media directory, copy files, generate thumbnails, and collect data entries
-        let (clutterlog_data, rss_items, generation_results, items_skipped) =
-            self.scan_and_copy_media(&source_media_path, &build_media_path, &library, base_url)?;
+        let archive = collect_archive(
+            entries.iter().map(|e| e.item.datetime.as_str()),
+            base_url,
+            DEFAULT_ARCHIVE_DIR,
+        );
+
+        let templates = Templates::new(&self.path);
+        let mut sitemap: Vec<SitemapUrl> = Vec::new();
 
         // Render index.html from template
-        let rendered = TEMPLATE_INDEX
-            .replace("{{title}}", &escape_html(&self.info.title))
-            .replace("{{description}}", &escape_html(&self.info.description))
-            .replace("{{author}}", &escape_html(&self.info.author))
-            .replace("{{clutterlog_data}}", &clutterlog_data);
-
-        let index_path = build_path.join("index.html");
-        fs::write(&index_path, &rendered).map_err(|e| WebsiteError::Io(index_path, e))?;
-
-        // Render and write feed.xml
-        let rss_items_str = if rss_items.is_empty() {
-            String::new()
-        } else {
-            format!("{}\n", rss_items.join("\n"))
-        };
-        let feed_url = format!("{}/", base_url);
-        let rss_rendered = TEMPLATE_RSS
-            .replace("{{title}}", &escape_html(&self.info.title))
-            .replace("{{url}}", &escape_html(&feed_url))
-            .replace("{{description}}", &escape_html(&self.info.description))
-            .replace("{{items}}", &rss_items_str);
+        sitemap.push(self.write_gallery(
+            &templates,
+            &mut manifest,
+            &build_path,
+            base_url,
+            &archive,
+            Gallery {
+                template: "index.html",
+                dir: String::new(),
+                root: String::new(),
+                entries: entries.iter().collect(),
+                context: context! {
+                    albums => &albums,
+                    tags => &tags,
+                    archive => &archive,
+                },
+            },
+        )?);
 
-        let rss_path = build_path.join(DEFAULT_FEED_FILE);
-        fs::write(&rss_path, &rss_rendered).map_err(|e| WebsiteError::Io(rss_path, e))?;
+        // Render one gallery page per album
+        for album in &albums {
+            let album_entries: Vec<&MediaEntry> = entries
+                .iter()
+                .filter(|e| e.page.album.as_deref() == Some(album.path.as_str()))
+                .collect();
+            let album_tags = collect_tags(
+                album_entries
+                    .iter()
+                    .flat_map(|e| e.page.tags.iter().map(|t| t.as_str())),
+                base_url,
+                DEFAULT_TAGS_DIR,
+            );
+            let sub_albums: Vec<&Album> = albums
+                .iter()
+                .filter(|a| a.is_nested_in(&album.path))
+                .collect();
+
+            sitemap.push(self.write_gallery(
+                &templates,
+                &mut manifest,
+                &build_path,
+                base_url,
+                &archive,
+                Gallery {
+                    template: "album.html",
+                    dir: format!("{}/{}/", DEFAULT_ALBUMS_DIR, album.path),
+                    root: album.root_prefix(),
+                    entries: album_entries,
+                    context: context! {
+                        album => album,
+                        albums => sub_albums,
+                        tags => album_tags,
+                    },
+                },
+            )?);
+        }
+
+        // Render a gallery page and a feed per tag
+        for tag in &tags {
+            let tagged = || {
+                entries
+                    .iter()
+                    .filter(|e| e.page.tags.iter().any(|t| tag.matches(t)))
+            };
+
+            let tag_path = format!("{}/{}", DEFAULT_TAGS_DIR, tag.slug);
+            sitemap.push(self.write_gallery(
+                &templates,
+                &mut manifest,
+                &build_path,
+                base_url,
+                &archive,
+                Gallery {
+                    template: "tag.html",
+                    dir: format!("{}/", tag_path),
+                    root: "../../".to_string(),
+                    entries: tagged().collect(),
+                    context: context! {
+                        tag => tag,
+                    },
+                },
+            )?);
+
+            self.write_feeds(
+                &templates,
+                &mut manifest,
+                &build_path,
+                Feed {
+                    dir: format!("{}/", tag_path),
+                    url: &tag.url,
+                    tag: Some(tag),
+                    items: tagged().map(|e| &e.feed).collect(),
+                },
+            )?;
+        }
+
+        // Render a gallery page per year and month
+        for period in archive
+            .iter()
+            .flat_map(|year| std::iter::once(year).chain(&year.months))
+        {
+            sitemap.push(
+                self.write_gallery(
+                    &templates,
+                    &mut manifest,
+                    &build_path,
+                    base_url,
+                    &archive,
+                    Gallery {
+                        template: "archive.html",
+                        dir: period.dir.clone(),
+                        root: period.root_prefix(),
+                        entries: entries
+                            .iter()
+                            .filter(|e| period.contains(&e.item.datetime))
+                            .collect(),
+                        context: context! {
+                            period => period,
+                        },
+                    },
+                )?,
+            );
+        }
+
+        // Render a permalink page per item, linked to its neighbours in gallery order
+        // and back to the home gallery page listing it
+        let pages: Vec<&ItemPage> = entries.iter().map(|e| &e.page).collect();
+        let pages_root = "../../";
+
+        for (i, page) in pages.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|j| pages[j]);
+            let next = pages.get(i + 1).copied();
+            let gallery_page = match self.info.page_size {
+                0 => 1,
+                size => i / size + 1,
+            };
+            let gallery_url = format!("{}/{}", base_url, page_dir("", gallery_page));
+
+            let item_tags: Vec<&Tag> = tags
+                .iter()
+                .filter(|tag| page.tags.iter().any(|t| tag.matches(t)))
+                .collect();
+
+            let rendered = templates.render(
+                "item.html",
+                context! {
+                    site => &self.info,
+                    base_url => base_url,
+                    root => pages_root,
+                    item => page,
+                    item_tags => item_tags,
+                    prev => prev,
+                    next => next,
+                    gallery_url => gallery_url,
+                },
+            )?;
+
+            let page_path = format!("{}/{}/index.html", DEFAULT_PAGES_DIR, page.slug);
+            manifest.write(&build_path, &page_path, &rendered)?;
+            sitemap.push(SitemapUrl {
+                loc: page.url.clone(),
+                lastmod: Some(page.datetime.clone()),
+            });
+        }
+
+        // Render and write the site feeds
+        self.write_feeds(
+            &templates,
+            &mut manifest,
+            &build_path,
+            Feed {
+                dir: String::new(),
+                url: &format!("{}/", base_url),
+                tag: None,
+                items: entries.iter().map(|e| &e.feed).collect(),
+            },
+        )?;
+
+        // Point crawlers at the sitemap, or keep them out entirely
+        if self.info.indexing {
+            let rendered = templates.render(
+                "sitemap.xml",
+                context! {
+                    site => &self.info,
+                    urls => sitemap,
+                },
+            )?;
+            manifest.write(&build_path, SITEMAP_FILE, &rendered)?;
+        }
+        let rendered = templates.render(
+            "robots.txt",
+            context! {
+                site => &self.info,
+                sitemap_url => format!("{}/{}", base_url, SITEMAP_FILE),
+            },
+        )?;
+        manifest.write(&build_path, ROBOTS_FILE, &rendered)?;
 
         // Write static assets
-        let style_path = public_path.join("style.css");
-        fs::write(&style_path, TEMPLATE_STYLE).map_err(|e| WebsiteError::Io(style_path, e))?;
+        for (name, _) in DEFAULT_ASSETS {
+            let content = templates.asset(name)?;
+            manifest.write(&build_path, name, &content)?;
+        }
 
-        let js_path = public_path.join("clutterlog.js");
-        fs::write(&js_path, TEMPLATE_JS).map_err(|e| WebsiteError::Io(js_path, e))?;
+        // Drop whatever earlier builds produced that this one didn't
+        let files_removed = manifest.prune(&build_path, &previous_manifest, DEFAULT_MEDIA_DIR)?;
+        manifest.save(&manifest_path, &build_path)?;
 
         Ok(BuildReport::from_results(
-            generation_results,
+            &generation_results,
             items_skipped,
+            files_removed,
+            manifest.changed().to_vec(),
             start.elapsed(),
         ))
     }
 
+    /// Renders a gallery split into pages of `page_size` items. The first page is
+    /// the gallery's own `index.html`, later ones go to `page/<n>/`. Every page
+    /// lists its items as static HTML for browsers without JavaScript, and gets a
+    /// `data.json` chunk that links to the next one for infinite scrolling.
+    /// Returns the gallery's sitemap entry.
+    fn write_gallery(
+        &self,
+        templates: &Templates,
+        manifest: &mut BuildManifest,
+        build_path: &Path,
+        base_url: &str,
+        archive: &[Period],
+        gallery: Gallery,
+    ) -> Result<SitemapUrl, WebsiteError> {
+        let chunks: Vec<&[&MediaEntry]> = match self.info.page_size {
+            _ if gallery.entries.is_empty() => vec![&[]],
+            0 => vec![&gallery.entries],
+            size => gallery.entries.chunks(size).collect(),
+        };
+        let pages = chunks.len();
+        let page_url = |number: usize| {
+            format!(
+                "{}/{}",
+                base_url,
+                encode_path(&page_dir(&gallery.dir, number))
+            )
+        };
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let number = i + 1;
+            let dir = page_dir(&gallery.dir, number);
+            let root = if number == 1 {
+                gallery.root.clone()
+            } else {
+                format!("{}../../", gallery.root)
+            };
+
+            let data = GalleryChunk {
+                page: number,
+                pages,
+                next: (number < pages)
+                    .then(|| format!("{}{}", page_url(number + 1), GALLERY_DATA_FILE)),
+                sections: sections(archive, chunk.iter().map(|e| e.item.datetime.as_str())),
+                items: chunk.iter().map(|e| &e.item).collect(),
+            };
+            let json = if self.info.relative_urls {
+                // Relative to the chunk's own page, which sits next to it
+                let mut value = serde_json::to_value(&data).map_err(WebsiteError::Json)?;
+                relative_json(&mut value, base_url, &root);
+                serde_json::to_string(&value)
+            } else {
+                serde_json::to_string(&data)
+            }
+            .map_err(WebsiteError::Json)?;
+            manifest.write(build_path, &format!("{}{}", dir, GALLERY_DATA_FILE), &json)?;
+
+            let pagination = Pagination {
+                page: number,
+                pages,
+                prev_url: (number > 1).then(|| page_url(number - 1)),
+                next_url: (number < pages).then(|| page_url(number + 1)),
+            };
+            let items: Vec<&ItemPage> = chunk.iter().map(|e| &e.page).collect();
+            let rendered = templates.render(
+                gallery.template,
+                context! {
+                    site => &self.info,
+                    base_url => base_url,
+                    root => &root,
+                    items => items,
+                    pagination => pagination,
+                    sections => &data.sections,
+                    data_url => format!("{}{}{}", root, encode_path(&dir), GALLERY_DATA_FILE),
+                    clutterlog_data => self.info.inline_data.then(|| script_json(&json)),
+                    ..gallery.context.clone()
+                },
+            )?;
+            manifest.write(build_path, &format!("{}index.html", dir), &rendered)?;
+        }
+        Ok(SitemapUrl {
+            loc: page_url(1),
+            // Entries are newest first
+            lastmod: gallery.entries.first().map(|e| e.page.datetime.clone()),
+        })
+    }
+
+    /// Writes the feeds enabled in `site.toml`, listing the newest `limit` items.
+    fn write_feeds(
+        &self,
+        templates: &Templates,
+        manifest: &mut BuildManifest,
+        build_path: &Path,
+        feed: Feed,
+    ) -> Result<(), WebsiteError> {
+        let Feed {
+            dir,
+            url: feed_url,
+            tag,
+            mut items,
+        } = feed;
+        let feeds = &self.info.feeds;
+        if feeds.limit > 0 {
+            items.truncate(feeds.limit);
+        }
+
+        if feeds.rss {
+            let rendered = templates.render(
+                "rss.xml",
+                context! {
+                    site => &self.info,
+                    tag => tag,
+                    feed_url => feed_url,
+                    items => &items,
+                },
+            )?;
+            manifest.write(
+                build_path,
+                &format!("{}{}", dir, DEFAULT_FEED_FILE),
+                &rendered,
+            )?;
+        }
+
+        if feeds.atom {
+            // Items are newest first, so the first one dates the feed
+            let updated = items.first().map_or_else(
+                || chrono::Utc::now().to_rfc3339(),
+                |item| item.published.clone(),
+            );
+            let rendered = templates.render(
+                "atom.xml",
+                context! {
+                    site => &self.info,
+                    tag => tag,
+                    feed_url => feed_url,
+                    updated => updated,
+                    items => &items,
+                },
+            )?;
+            manifest.write(build_path, &format!("{}{}", dir, ATOM_FEED_FILE), &rendered)?;
+        }
+
+        if feeds.json {
+            let title = match tag {
+                Some(tag) => format!("{} - #{}", self.info.title, tag.name),
+                None => self.info.title.clone(),
+            };
+            let feed = JsonFeed::new(&self.info, title, feed_url, JSON_FEED_FILE, &items);
+            let json = serde_json::to_string_pretty(&feed).map_err(WebsiteError::Json)?;
+            manifest.write(build_path, &format!("{}{}", dir, JSON_FEED_FILE), &json)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the build directory and everything cached for it. Returns `false` if
+    /// there was nothing to delete.
+    pub fn clean(&self) -> Result<bool, WebsiteError> {
+        let mut removed = false;
+        for path in [
+            self.path.join(DEFAULT_BUILD_DIR),
+            cache_dir(&self.path),
+            preview_dir(&self.path),
+        ] {
+            if path.exists() {
+                fs::remove_dir_all(&path).map_err(|e| WebsiteError::Io(path, e))?;
+                removed = true;
+            }
+        }
+        for path in [
+            manifest_path(&self.path, false),
+            manifest_path(&self.path, true),
+        ] {
+            if path.exists() {
+                fs::remove_file(&path).map_err(|e| WebsiteError::Io(path, e))?;
+                removed = true;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Publishes the media of `media/` as `output` says. Unpublished items are
+    /// skipped entirely unless `drafts` is set.
     fn scan_and_copy_media(
         &self,
-        source_path: &Path,
-        dest_path: &Path,
+        output: &MediaOutput,
         library: &MediaLibrary,
         base_url: &str,
-    ) -> Result<(String, Vec<String>, Vec<GenerationResult>, usize), WebsiteError> {
+        drafts: bool,
+    ) -> Result<ScannedMedia, WebsiteError> {
+        let source_path = self.path.join(DEFAULT_MEDIA_DIR);
         if !source_path.exists() {
-            return Ok(("[]".to_string(), Vec::new(), Vec::new(), 0));
+            return Ok(ScannedMedia {
+                entries: Vec::new(),
+                published: PublishedMedia::default(),
+                items_skipped: 0,
+            });
         }
 
-        let dir_entries = fs::read_dir(source_path)
-            .map_err(|e| WebsiteError::Io(source_path.to_path_buf(), e))?;
-
-        // Collect directory entries so we can process them in parallel
-        let items: Vec<(PathBuf, Option<String>)> = dir_entries
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let path = entry.path();
-                let filename = path.file_name().and_then(|n| n.to_str())?;
-                let datetime = library.get_datetime(filename).map(|s| s.to_string());
-                Some((path, datetime))
-            })
-            .collect();
+        // Collect media files (recursively) so we can process them in parallel.
+        // Slugs come from the library, which keeps them stable between builds. Only
+        // a file it doesn't know yet gets one on the spot.
+        let mut used_slugs: HashSet<String> =
+            library.entries.iter().map(|e| e.slug.clone()).collect();
+        let items: Vec<(String, PathBuf, String, Option<MetaMedia>)> =
+            scan_media_files(&source_path)
+                .map_err(|e| WebsiteError::Io(source_path.clone(), e))?
+                .into_iter()
+                .map(|(name, path)| {
+                    let meta = library.get(&name).cloned();
+                    let slug = match &meta {
+                        Some(meta) if !meta.slug.is_empty() => meta.slug.clone(),
+                        _ => unique_slug(&name, &mut used_slugs),
+                    };
+                    (name, path, slug, meta)
+                })
+                .collect();
 
-        // Process items in parallel: copy files and generate thumbnails (skipping up-to-date items)
-        // Each result includes a bool indicating whether the item was skipped.
-        let processed: Vec<Result<(GenerationResult, String, String, bool), WebsiteError>> = items
+        // Process items in parallel: publish derivatives, generating the ones the cache
+        // doesn't hold, unless the item is known to be published already. Each result
+        // includes a bool indicating whether the item was skipped (nothing generated).
+        type Processed = (PublishedItem, MediaEntry, String, bool);
+        let processed: Vec<Result<Processed, WebsiteError>> = items
             .par_iter()
-            .filter_map(|(path, datetime)| {
-                let item = WebsiteMedia::from_path(path, datetime.as_deref())?;
+            .filter_map(|(name, path, slug, meta)| {
+                let item = match WebsiteMedia::from_path(
+                    path,
+                    name,
+                    slug,
+                    meta.as_ref(),
+                    &self.info.images,
+                    self.info.timezone,
+                    output.cache,
+                )
+                .transpose()?
+                {
+                    Ok(item) => item,
+                    Err(e) => return Some(Err(e)),
+                };
+                if item.unpublished.is_some() && !drafts {
+                    return None;
+                }
 
-                let (result, skipped) = if item.is_up_to_date(dest_path) {
-                    (item.read_existing_sizes(dest_path), true)
-                } else {
-                    (item.copy_and_generate_thumb(dest_path), false)
+                let published = match output.published.current(name, output.rebuild) {
+                    Some(published) => Ok((published.clone(), true)),
+                    None => match output.cache.hash_source(name, path) {
+                        Ok(source) => item
+                            .publish(
+                                output.dest_path,
+                                DEFAULT_MEDIA_DIR,
+                                output.cache,
+                                &source.hash,
+                                output.previous,
+                            )
+                            .map(|(result, skipped)| (PublishedItem { source, result }, skipped)),
+                        Err(e) => Err(WebsiteError::Io(path.clone(), e)),
+                    },
                 };
 
-                let entry = item.to_json_entry(base_url, DEFAULT_MEDIA_DIR);
-                let rss_item = item.to_rss_item(base_url, DEFAULT_MEDIA_DIR);
+                let mut entry = MediaEntry {
+                    page: item.to_page(base_url, DEFAULT_MEDIA_DIR, DEFAULT_PAGES_DIR),
+                    item: item.to_item(base_url, DEFAULT_MEDIA_DIR, DEFAULT_PAGES_DIR),
+                    feed: item.to_feed_item(base_url, DEFAULT_MEDIA_DIR, DEFAULT_PAGES_DIR),
+                };
                 let image_url = item.image_url(base_url, DEFAULT_MEDIA_DIR);
-                Some(result.map(|mut r| {
-                    r.image_url = image_url;
-                    (r, entry, rss_item, skipped)
+                Some(published.map(|(mut published, skipped)| {
+                    published.result.image_url = image_url;
+                    entry.feed.enclosure_length = published.result.image_size;
+                    (published, entry, name.clone(), skipped)
                 }))
             })
             .collect();
 
         // Collect results, propagating any errors
-        let mut results: Vec<GenerationResult> = Vec::new();
-        let mut entries: Vec<String> = Vec::new();
-        let mut rss_items: Vec<String> = Vec::new();
-        let mut items_skipped: usize = 0;
+        let mut scanned = ScannedMedia {
+            entries: Vec::new(),
+            published: PublishedMedia::default(),
+            items_skipped: 0,
+        };
         for item_result in processed {
-            let (gen_result, entry, rss_item, skipped) = item_result?;
+            let (published, entry, name, skipped) = item_result?;
             if skipped {
-                items_skipped += 1;
+                scanned.items_skipped += 1;
             }
-            results.push(gen_result);
-            entries.push(entry);
-            rss_items.push(rss_item);
+            scanned.entries.push(entry);
+            scanned.published.items.insert(name, published);
         }
 
-        let json = if entries.is_empty() {
-            "[]".to_string()
-        } else {
-            format!("[\n{}\n        ]", entries.join(",\n"))
-        };
+        Ok(scanned)
+    }
+}
+
+/// Where a build puts its output.
+#[derive(Clone)]
+pub enum BuildTarget {
+    /// Everything goes into `build/`, ready to deploy.
+    Deploy,
+    /// Pages, JSON and feeds are kept in the store, and media is published to
+    /// `.clutterlog/preview/`, so `build/` is left alone.
+    Preview(PreviewStore),
+}
+
+impl BuildTarget {
+    /// The directory the build publishes media into.
+    pub fn dir(&self, site_path: &Path) -> PathBuf {
+        match self {
+            BuildTarget::Deploy => site_path.join(DEFAULT_BUILD_DIR),
+            BuildTarget::Preview(_) => preview_dir(site_path),
+        }
+    }
+
+    /// Where the build records what it published.
+    pub fn manifest_path(&self, site_path: &Path) -> PathBuf {
+        manifest_path(site_path, matches!(self, BuildTarget::Preview(_)))
+    }
+
+    pub fn store(&self) -> Option<PreviewStore> {
+        match self {
+            BuildTarget::Deploy => None,
+            BuildTarget::Preview(store) => Some(store.clone()),
+        }
+    }
+}
+
+/// What a change to the site requires rebuilding, from cheapest to most thorough.
+pub enum Rebuild {
+    /// Sidecars, metadata or templates changed: render the pages again, reusing
+    /// the media the previous build published.
+    Pages,
+    /// These media files, named relative to `media/`, were added, changed or
+    /// removed: publish them, then render the pages.
+    Media(BTreeSet<String>),
+    /// Publish every item again, e.g. after `site.toml` changed.
+    Full,
+}
+
+impl Rebuild {
+    /// A rebuild covering both `self` and `other`.
+    pub fn merge(self, other: Rebuild) -> Rebuild {
+        match (self, other) {
+            (Rebuild::Full, _) | (_, Rebuild::Full) => Rebuild::Full,
+            (Rebuild::Media(mut names), Rebuild::Media(other)) => {
+                names.extend(other);
+                Rebuild::Media(names)
+            }
+            (Rebuild::Media(names), Rebuild::Pages) | (Rebuild::Pages, Rebuild::Media(names)) => {
+                Rebuild::Media(names)
+            }
+            (Rebuild::Pages, Rebuild::Pages) => Rebuild::Pages,
+        }
+    }
+}
+
+/// The media a build published, by name relative to `media/`. Kept between the
+/// builds of `server --watch` so unchanged items aren't published again.
+#[derive(Default)]
+pub struct PublishedMedia {
+    items: BTreeMap<String, PublishedItem>,
+}
+
+#[derive(Clone)]
+struct PublishedItem {
+    source: SourceHash,
+    result: GenerationResult,
+}
+
+impl PublishedMedia {
+    /// The publication of `name` by the previous build, if `rebuild` says it's
+    /// still current.
+    fn current(&self, name: &str, rebuild: &Rebuild) -> Option<&PublishedItem> {
+        match rebuild {
+            Rebuild::Full => None,
+            Rebuild::Media(changed) if changed.contains(name) => None,
+            _ => self.items.get(name),
+        }
+    }
+
+    fn sources(&self) -> BTreeMap<String, SourceHash> {
+        self.items
+            .iter()
+            .map(|(name, item)| (name.clone(), item.source.clone()))
+            .collect()
+    }
+}
+
+/// Where `scan_and_copy_media` publishes derivatives, and what it can skip.
+struct MediaOutput<'a> {
+    /// The media directory inside the build.
+    dest_path: &'a Path,
+    cache: &'a BuildCache,
+    /// The previous build's manifest, telling which outputs are current.
+    previous: &'a BuildManifest,
+    published: &'a PublishedMedia,
+    rebuild: &'a Rebuild,
+}
+
+/// What `scan_and_copy_media` collects.
+struct ScannedMedia {
+    entries: Vec<MediaEntry>,
+    published: PublishedMedia,
+    /// Items that were up to date, nothing was generated or copied for them.
+    items_skipped: usize,
+}
+
+/// A page listing items: the home page, an album or a tag.
+struct Gallery<'a> {
+    template: &'a str,
+    /// Directory inside the build, empty or ending in `/`.
+    dir: String,
+    /// Relative prefix from `dir` back to the site root.
+    root: String,
+    entries: Vec<&'a MediaEntry>,
+    /// Template variables specific to the gallery.
+    context: minijinja::Value,
+}
+
+/// A page listed in `sitemap.xml`.
+#[derive(Serialize)]
+struct SitemapUrl {
+    loc: String,
+    /// RFC 3339 capture time of the newest item on the page.
+    lastmod: Option<String>,
+}
 
-        Ok((json, rss_items, results, items_skipped))
+/// The feeds of a gallery: the whole site or a tag.
+struct Feed<'a> {
+    /// Directory inside the build, empty or ending in `/`.
+    dir: String,
+    /// The page the feeds belong to, ending in `/`.
+    url: &'a str,
+    tag: Option<&'a Tag>,
+    /// Newest first.
+    items: Vec<&'a FeedItem>,
+}
+
+/// A processed media item with everything the pages and feeds need from it.
+struct MediaEntry {
+    page: ItemPage,
+    item: MediaItem,
+    feed: FeedItem,
+}
+
+/// Adds the lines of the `.gitignore` template that the site's `.gitignore` is
+/// missing, creating it if needed, so the regenerated directories aren't committed.
+fn write_gitignore(site_path: &Path) -> Result<(), WebsiteError> {
+    let path = site_path.join(".gitignore");
+    let existing = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(WebsiteError::Io(path, e)),
+    };
+    let missing: Vec<&str> = TEMPLATE_GITIGNORE
+        .lines()
+        .filter(|line| !line.starts_with('#') && !existing.lines().any(|l| l.trim() == *line))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut content = existing;
+    if content.is_empty() {
+        content.push_str(TEMPLATE_GITIGNORE);
+    } else {
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        for line in missing {
+            content.push_str(line);
+            content.push('\n');
+        }
     }
+    fs::write(&path, content).map_err(|e| WebsiteError::Io(path, e))
+}
+
+/// Serializes JSON for embedding in an inline `<script>`. JSON only allows these
+/// characters inside strings, where the escapes mean the same, so nothing in the
+/// data can close the script element or open an HTML comment.
+fn script_json(json: &str) -> String {
+    json.replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026")
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029")
 }
 
 // Error
@@ -310,9 +1033,14 @@ pub enum WebsiteError {
     MediaLibrary(Box<MediaLibraryError>),
     Io(PathBuf, io::Error),
     Serialize(toml::ser::Error),
+    Json(serde_json::Error),
     Image(PathBuf, image::ImageError),
     Ffmpeg(PathBuf, String),
     FfmpegNotFound(String),
+    Decode(PathBuf, String),
+    PublishAfter(PathBuf, String),
+    Template(Box<minijinja::Error>),
+    MissingAsset(String),
 }
 
 impl std::fmt::Display for WebsiteError {
@@ -342,6 +1070,7 @@ impl std::fmt::Display for WebsiteError {
                 write!(f, "failed to write '{}': {}", path.display(), err)
             }
             WebsiteError::Serialize(err) => write!(f, "failed to serialize site info: {}", err),
+            WebsiteError::Json(err) => write!(f, "failed to serialize JSON: {}", err),
             WebsiteError::Image(path, err) => {
                 write!(f, "failed to process image '{}': {}", path.display(), err)
             }
@@ -356,6 +1085,18 @@ impl std::fmt::Display for WebsiteError {
             WebsiteError::FfmpegNotFound(err) => {
                 write!(f, "looks like `ffmpeg` is not installed: {}", err)
             }
+            WebsiteError::Decode(path, err) => {
+                write!(f, "failed to decode '{}': {}", path.display(), err)
+            }
+            WebsiteError::PublishAfter(path, err) => {
+                write!(f, "invalid publish_after for '{}': {}", path.display(), err)
+            }
+            WebsiteError::Template(err) => {
+                write!(f, "failed to render template: {:#}", err)
+            }
+            WebsiteError::MissingAsset(name) => {
+                write!(f, "no built-in asset named '{}'", name)
+            }
         }
     }
 }
@@ -374,9 +1115,22 @@ impl From<MediaLibraryError> for WebsiteError {
     }
 }
 
-fn escape_html(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json_for_scripts() {
+        let json = serde_json::to_string("</script><!-- & \u{2028}\u{2029}").unwrap();
+        let escaped = script_json(&json);
+        assert_eq!(
+            escaped,
+            r#""\u003c/script\u003e\u003c!-- \u0026 \u2028\u2029""#
+        );
+        assert!(!escaped.contains('<') && !escaped.contains('\u{2028}'));
+        assert_eq!(
+            serde_json::from_str::<String>(&escaped).unwrap(),
+            "</script><!-- & \u{2028}\u{2029}"
+        );
+    }
 }
@@ -2,12 +2,22 @@ use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use image::ImageFormat;
 use serde::{Deserialize, Serialize};
 
+use super::media_date::parse_offset;
+
 pub const SITE_TOML: &str = "site.toml";
 const DEFAULT_DESCRIPTION: &str = "An uncurated timeline of unfinished projects";
 const DEFAULT_AUTHOR: &str = "author-name";
 const DEFAULT_URL: &str = "https://localhost:8088/";
+const DEFAULT_DISPLAY_SIZES: &[u32] = &[1280, 2560];
+const DEFAULT_DISPLAY_QUALITY: u8 = 85;
+const DEFAULT_THUMB_QUALITY: u8 = 80;
+const DEFAULT_PAGE_SIZE: usize = 120;
+const DEFAULT_FEED_LIMIT: usize = 50;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WebsiteInfo {
@@ -15,6 +25,194 @@ pub struct WebsiteInfo {
     pub description: String,
     pub author: String,
     pub url: String,
+    /// Timezone of dates that don't carry their own offset.
+    #[serde(default)]
+    pub timezone: Timezone,
+    /// Items per gallery page, `0` puts every item on a single page.
+    #[serde(default = "default_page_size")]
+    pub page_size: usize,
+    /// Also embed each gallery's items in its page, saving the `data.json` request.
+    #[serde(default)]
+    pub inline_data: bool,
+    /// Let search engines index the site. When off, `robots.txt` disallows
+    /// everything, pages ask not to be indexed and no sitemap is written.
+    #[serde(default = "default_indexing")]
+    pub indexing: bool,
+    /// Link pages and media with paths relative to each page, so one build can be
+    /// served from any host or subpath. Feeds, the sitemap and OpenGraph tags keep
+    /// absolute URLs.
+    #[serde(default)]
+    pub relative_urls: bool,
+    #[serde(default)]
+    pub images: ImageSettings,
+    #[serde(default)]
+    pub feeds: FeedSettings,
+}
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+fn default_indexing() -> bool {
+    true
+}
+
+/// The site's timezone: an IANA name like `"Europe/Berlin"`, whose offset follows
+/// daylight saving time, or a fixed UTC offset like `"+02:00"`, `"-05:30"` or `"UTC"`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Timezone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Timezone {
+    /// The UTC offset in effect at `instant`.
+    pub fn offset_at(&self, instant: DateTime<Utc>) -> FixedOffset {
+        match self {
+            Self::Fixed(offset) => *offset,
+            Self::Named(tz) => tz.offset_from_utc_datetime(&instant.naive_utc()).fix(),
+        }
+    }
+
+    /// The UTC offset of the wall-clock time `local`. A time repeated when the clocks
+    /// go back takes the earlier offset, one skipped when they go forward the offset
+    /// from before the change.
+    pub fn offset_of_local(&self, local: NaiveDateTime) -> FixedOffset {
+        match self {
+            Self::Fixed(offset) => *offset,
+            Self::Named(tz) => match tz.offset_from_local_datetime(&local).earliest() {
+                Some(offset) => offset.fix(),
+                // A day earlier is safely before the change
+                None => tz
+                    .offset_from_utc_datetime(&(local - TimeDelta::days(1)))
+                    .fix(),
+            },
+        }
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Self::Fixed(FixedOffset::east_opt(0).expect("zero is a valid offset"))
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(offset) = parse_offset(&value) {
+            return Ok(Self::Fixed(offset));
+        }
+        value.trim().parse().map(Self::Named).map_err(|_| {
+            format!(
+                "invalid timezone '{}', expected a name like \"Europe/Berlin\" or a UTC offset like \"+02:00\"",
+                value
+            )
+        })
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        match timezone {
+            Timezone::Fixed(offset) if offset.local_minus_utc() == 0 => "UTC".to_string(),
+            Timezone::Fixed(offset) => offset.to_string(),
+            Timezone::Named(tz) => tz.name().to_string(),
+        }
+    }
+}
+
+/// The `[images]` table of `site.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageSettings {
+    /// Widths of the resized copies shown in the lightbox. Empty publishes originals only.
+    pub display_sizes: Vec<u32>,
+    /// Also copy the untouched original file into the build.
+    pub keep_original: bool,
+    pub display_format: OutputFormat,
+    /// Encoder quality (1-100) for lossy display formats.
+    pub display_quality: u8,
+    /// Format of still-image thumbnails. Animated thumbnails are always WebP.
+    pub thumb_format: OutputFormat,
+    /// Encoder quality (1-100) for lossy thumbnail formats.
+    pub thumb_quality: u8,
+}
+
+impl Default for ImageSettings {
+    fn default() -> Self {
+        Self {
+            display_sizes: DEFAULT_DISPLAY_SIZES.to_vec(),
+            keep_original: false,
+            display_format: OutputFormat::Original,
+            display_quality: DEFAULT_DISPLAY_QUALITY,
+            thumb_format: OutputFormat::Original,
+            thumb_quality: DEFAULT_THUMB_QUALITY,
+        }
+    }
+}
+
+/// The `[feeds]` table of `site.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeedSettings {
+    /// Write `feed.xml` (RSS 2.0).
+    pub rss: bool,
+    /// Write `atom.xml` (Atom 1.0).
+    pub atom: bool,
+    /// Write `feed.json` (JSON Feed 1.1).
+    pub json: bool,
+    /// Newest items listed in each feed, `0` lists every item.
+    pub limit: usize,
+}
+
+impl Default for FeedSettings {
+    fn default() -> Self {
+        Self {
+            rss: true,
+            atom: true,
+            json: true,
+            limit: DEFAULT_FEED_LIMIT,
+        }
+    }
+}
+
+/// Encoding used for generated images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Same format as the source file.
+    Original,
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    /// Resolves the concrete format for a source with the given (lowercase) extension.
+    pub fn image_format(self, source_extension: &str) -> ImageFormat {
+        match self {
+            OutputFormat::Original => source_format(source_extension),
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+}
+
+/// Web-displayable format matching a source extension. Anything that isn't a
+/// plain PNG, WebP or AVIF (TIFF, HEIC, ...) is re-encoded as JPEG.
+pub fn source_format(extension: &str) -> ImageFormat {
+    match extension {
+        "png" => ImageFormat::Png,
+        "webp" => ImageFormat::WebP,
+        "avif" => ImageFormat::Avif,
+        _ => ImageFormat::Jpeg,
+    }
 }
 
 impl WebsiteInfo {
@@ -24,6 +222,13 @@ impl WebsiteInfo {
             description: DEFAULT_DESCRIPTION.to_string(),
             author: DEFAULT_AUTHOR.to_string(),
             url: DEFAULT_URL.to_string(),
+            timezone: Timezone::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+            inline_data: false,
+            indexing: true,
+            relative_urls: false,
+            images: ImageSettings::default(),
+            feeds: FeedSettings::default(),
         }
     }
 
@@ -59,3 +264,46 @@ impl std::fmt::Display for WebsiteInfoError {
 }
 
 impl std::error::Error for WebsiteInfoError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn reads_names_and_fixed_offsets() {
+        for (text, written) in [
+            ("Europe/Berlin", "Europe/Berlin"),
+            ("+02:00", "+02:00"),
+            ("-0530", "-05:30"),
+            ("utc", "UTC"),
+        ] {
+            let timezone = Timezone::try_from(text.to_string()).unwrap();
+            assert_eq!(String::from(timezone), written);
+        }
+        assert!(Timezone::try_from("Europe/Nowhere".to_string()).is_err());
+    }
+
+    #[test]
+    fn follows_daylight_saving_time() {
+        let berlin = Timezone::try_from("Europe/Berlin".to_string()).unwrap();
+        let winter = local("2024-01-15T12:00:00");
+        let summer = local("2024-07-15T12:00:00");
+        assert_eq!(berlin.offset_at(winter.and_utc()).to_string(), "+01:00");
+        assert_eq!(berlin.offset_at(summer.and_utc()).to_string(), "+02:00");
+        assert_eq!(berlin.offset_of_local(winter).to_string(), "+01:00");
+        assert_eq!(berlin.offset_of_local(summer).to_string(), "+02:00");
+
+        // 02:30 was skipped on 2024-03-31, and happened twice on 2024-10-27
+        let skipped = local("2024-03-31T02:30:00");
+        let repeated = local("2024-10-27T02:30:00");
+        assert_eq!(berlin.offset_of_local(skipped).to_string(), "+01:00");
+        assert_eq!(berlin.offset_of_local(repeated).to_string(), "+02:00");
+
+        let fixed = Timezone::try_from("+02:00".to_string()).unwrap();
+        assert_eq!(fixed.offset_of_local(winter).to_string(), "+02:00");
+    }
+}
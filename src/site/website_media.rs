@@ -1,52 +1,451 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::SystemTime;
-use std::{fs, io};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
 use image::imageops::FilterType;
+use image::metadata::Orientation;
 use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::Serialize;
 
+use super::build_cache::{BuildCache, derivative_key, partial_path};
+use super::build_manifest::BuildManifest;
+use super::media_date::{parse_datetime_arg, parse_offset};
+use super::media_library::MetaMedia;
+use super::url_path::encode_path;
 use super::website::WebsiteError;
+use super::website_info::{ImageSettings, Timezone, source_format};
 
-const ANIMATED_EXTENSIONS: &[&str] = &["gif", "webm", "mp4"];
-pub const SUPPORTED_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "gif", "webm", "mp4"];
+const ANIMATED_EXTENSIONS: &[&str] = &["gif", "apng", "webm", "mp4", "mov", "mkv"];
+const VIDEO_EXTENSIONS: &[&str] = &["webm", "mp4", "mov", "mkv"];
+/// Sources browsers can't display reliably. Images always get display copies in a
+/// web format; videos are transcoded to H.264 MP4.
+const CONVERTED_EXTENSIONS: &[&str] = &["heic", "heif", "tif", "tiff", "mov", "mkv"];
+/// Images the `image` crate can't decode; ffmpeg decodes them instead.
+const FFMPEG_DECODED_EXTENSIONS: &[&str] = &["heic", "heif", "avif"];
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "webp", "gif", "apng", "avif", "heic", "heif", "tif", "tiff", "svg",
+    "webm", "mp4", "mov", "mkv",
+];
 
 const THUMB_SIZE: u32 = 350;
+/// ravif speed (1-10). Slower presets barely shrink files further but take far longer.
+const AVIF_SPEED: u8 = 7;
+/// ffmpeg output options for MP4 transcodes of MOV/MKV sources.
+const TRANSCODE_ARGS: &[&str] = &[
+    "-c:v",
+    "libx264",
+    "-pix_fmt",
+    "yuv420p",
+    "-crf",
+    "23",
+    "-c:a",
+    "aac",
+    "-movflags",
+    "+faststart",
+];
+/// ffmpeg output options for animated thumbnails: the first two seconds, silent.
+const ANIMATED_THUMB_ARGS: &[&str] = &["-t", "2", "-c:v", "libwebp_anim", "-loop", "0", "-an"];
+/// Bump when generation changes in a way the recipe parameters don't capture
+/// (a different resize filter, say), to invalidate every cached derivative.
+const CACHE_VERSION: u32 = 1;
 
+/// Recursively lists supported media files under `media_path`.
+/// Returns `(name, path)` pairs where `name` is the `/`-separated path relative to
+/// `media_path`. Hidden files and directories are skipped.
+pub fn scan_media_files(media_path: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    scan_media_dir(media_path, "", &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn scan_media_dir(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        let name = format!("{}{}", prefix, file_name);
+
+        if path.is_dir() {
+            scan_media_dir(&path, &format!("{}/", name), files)?;
+            continue;
+        }
+
+        if is_supported_media(&path) {
+            files.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if `path` has one of the `SUPPORTED_EXTENSIONS` (any case).
+pub fn is_supported_media(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+#[derive(Clone)]
 pub struct GenerationResult {
     pub media_size: u64,
     pub thumb_size: u64,
     pub image_url: String,
+    /// Size of the file behind `image_url`, for feed enclosures.
+    pub image_size: u64,
+    /// Every file the item publishes, relative to the media output directory, with
+    /// the cache key it was published from.
+    pub files: Vec<(String, String)>,
+    /// Cache entries the item's derivatives were published from.
+    pub cached: Vec<PathBuf>,
+}
+
+/// How a published file is made from the source.
+#[derive(Clone, Copy)]
+enum Recipe {
+    Original,
+    Transcode,
+    Display { width: u32, format: ImageFormat },
+    Thumb { format: ImageFormat },
+    AnimatedThumb,
+}
+
+impl Recipe {
+    fn is_thumb(&self) -> bool {
+        matches!(self, Recipe::Thumb { .. } | Recipe::AnimatedThumb)
+    }
+
+    /// Everything that affects the output besides the source itself. Part of the
+    /// cache key, so changing any of it regenerates exactly the affected files.
+    fn params(&self, images: &ImageSettings) -> String {
+        let params = match self {
+            Recipe::Original => "original".to_string(),
+            Recipe::Transcode => format!("transcode {}", TRANSCODE_ARGS.join(" ")),
+            Recipe::Display { width, format } => format!(
+                "display {} {}",
+                width,
+                encoding_params(*format, images.display_quality)
+            ),
+            Recipe::Thumb { format } => format!(
+                "thumb {} {}",
+                THUMB_SIZE,
+                encoding_params(*format, images.thumb_quality)
+            ),
+            Recipe::AnimatedThumb => format!(
+                "animated-thumb {} {}",
+                animated_thumb_filter(),
+                ANIMATED_THUMB_ARGS.join(" ")
+            ),
+        };
+        format!("v{} {}", CACHE_VERSION, params)
+    }
+}
+
+/// A file an item publishes, relative to the media output directory.
+struct Derivative {
+    filename: String,
+    recipe: Recipe,
+}
+
+/// A feed entry, handed to the `rss.xml` and `atom.xml` templates and serialized
+/// into `feed.json`.
+#[derive(Serialize)]
+pub struct FeedItem {
+    pub title: String,
+    pub link: String,
+    pub guid: String,
+    /// RFC 822 capture time, for RSS.
+    pub pub_date: String,
+    /// RFC 3339 capture time, for Atom and JSON Feed.
+    pub published: String,
+    pub enclosure_url: String,
+    /// Byte length of the enclosure, filled in once it's published.
+    pub enclosure_length: u64,
+    pub mime_type: &'static str,
+    pub thumb_url: String,
+    pub album: Option<String>,
+    pub tags: Vec<String>,
+    pub description: String,
+    /// Pre-rendered HTML body, emitted inside a CDATA section.
+    pub content: String,
+}
+
+/// An item as the gallery script sees it, serialized into `data.json`.
+#[derive(Serialize)]
+pub struct MediaItem {
+    pub name: String,
+    pub album: Option<String>,
+    pub tags: Vec<String>,
+    /// Slugs of `tags`, which the tag bar filters on.
+    pub tag_slugs: Vec<String>,
+    /// Permalink page.
+    pub url: String,
+    pub image_url: String,
+    pub srcset: String,
+    pub sources: Vec<ImageSource>,
+    pub original_url: Option<String>,
+    pub thumb_url: String,
+    pub thumb_sources: Vec<ImageSource>,
+    pub title: String,
+    pub description: String,
+    /// Local wall-clock capture time, `%Y-%m-%dT%H:%M:%S`.
+    pub datetime: String,
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unpublished: Option<Unpublished>,
+}
+
+/// Everything the `item.html` permalink page needs to know about an item.
+#[derive(Serialize)]
+pub struct ItemPage {
+    pub name: String,
+    pub slug: String,
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    /// RFC 3339 capture time, with its UTC offset.
+    pub datetime: String,
+    /// Unix timestamp of `datetime`, for sorting items from different timezones.
+    pub timestamp: i64,
+    pub display_date: String,
+    pub image_url: String,
+    pub srcset: String,
+    pub sources: Vec<ImageSource>,
+    pub original_url: Option<String>,
+    pub thumb_url: String,
+    pub thumb_sources: Vec<ImageSource>,
+    pub mime_type: &'static str,
+    pub is_video: bool,
+    pub album: Option<String>,
+    pub tags: Vec<String>,
+    pub unpublished: Option<Unpublished>,
 }
 
 pub struct WebsiteMedia {
+    /// Path relative to the media directory, always `/`-separated (e.g. `project/sketch.png`).
     pub filename: String,
+    /// Relative directory the item lives in, `None` for top-level media.
+    pub album: Option<String>,
+    /// URL-safe identifier of the item's permalink page (`m/<slug>/`).
+    pub slug: String,
     pub title: String,
     pub description: String,
+    pub tags: Vec<String>,
+    /// Local wall-clock capture time, `%Y-%m-%dT%H:%M:%S`.
     pub datetime: String,
+    /// UTC offset of `datetime`.
+    pub offset: FixedOffset,
+    /// Set for hidden, draft and scheduled items.
+    pub unpublished: Option<Unpublished>,
     pub extension: String,
     pub source_path: PathBuf,
+    /// GIFs, animated PNGs and videos: published without resizing, with an
+    /// animated thumbnail.
+    pub animated: bool,
+    /// Widths of the resized display copies, smallest first. Empty for media that
+    /// is published as-is (animations, videos and SVGs).
+    pub display_widths: Vec<u32>,
+    /// Whether the untouched source file is copied into the build.
+    pub publish_original: bool,
+    images: ImageSettings,
+}
+
+/// The formats a set of generated images (display copies or thumbnails) is encoded
+/// in: the one every browser gets through `<img>`, plus an optional modern format
+/// offered through a `<picture>` `<source>`.
+struct Encodings {
+    fallback: ImageFormat,
+    modern: Option<ImageFormat>,
+}
+
+impl Encodings {
+    fn new(format: ImageFormat, source_extension: &str) -> Self {
+        let source = source_format(source_extension);
+        if matches!(format, ImageFormat::WebP | ImageFormat::Avif) && format != source {
+            Self {
+                fallback: source,
+                modern: Some(format),
+            }
+        } else {
+            Self {
+                fallback: format,
+                modern: None,
+            }
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = ImageFormat> {
+        std::iter::once(self.fallback).chain(self.modern)
+    }
+}
+
+/// A `<picture>` `<source>`: a MIME type and the matching `srcset`.
+#[derive(Serialize)]
+pub struct ImageSource {
+    pub mime_type: &'static str,
+    pub srcset: String,
+}
+
+/// Contents of a sidecar `.txt` file. The file may start with a front-matter
+/// block delimited by `---` lines holding `key: value` fields:
+///
+/// ```text
+/// ---
+/// tags: robot, 3d-print
+/// draft: true
+/// ---
+/// First print
+/// Came out warped, needs a brim.
+/// ```
+#[derive(Default)]
+struct Sidecar {
+    title: Option<String>,
+    description: String,
+    tags: Vec<String>,
+    hidden: bool,
+    draft: bool,
+    publish_after: Option<String>,
+}
+
+impl Sidecar {
+    fn read(txt_path: &Path) -> Self {
+        if !txt_path.is_file() {
+            return Self::default();
+        }
+        let content = fs::read_to_string(txt_path).unwrap_or_default();
+        let mut sidecar = Self::default();
+
+        let mut lines: Vec<&str> = content.lines().collect();
+        if lines.first().is_some_and(|l| l.trim() == "---")
+            && let Some(end) = lines.iter().skip(1).position(|l| l.trim() == "---")
+        {
+            for line in &lines[1..=end] {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim();
+                match key.trim() {
+                    "tags" => {
+                        sidecar.tags = value
+                            .trim_start_matches('[')
+                            .trim_end_matches(']')
+                            .split(',')
+                            .map(|t| t.trim().trim_matches('"').to_string())
+                            .filter(|t| !t.is_empty())
+                            .collect();
+                    }
+                    "hidden" => sidecar.hidden = is_true(value),
+                    "draft" => sidecar.draft = is_true(value),
+                    "publish_after" => {
+                        sidecar.publish_after = Some(value.trim_matches('"').to_string());
+                    }
+                    _ => {}
+                }
+            }
+            lines.drain(..=end + 1);
+        }
+
+        // With two or more lines the first one is the title, otherwise it's all description
+        if lines.len() >= 2 {
+            sidecar.title = Some(lines[0].trim().to_string());
+            sidecar.description = lines[1..].join("\n").trim().to_string();
+        } else if lines.len() == 1 {
+            sidecar.description = lines[0].trim().to_string();
+        }
+
+        sidecar
+    }
+}
+
+/// Front-matter flags are set with `true` or `yes`.
+fn is_true(value: &str) -> bool {
+    matches!(
+        value.trim_matches('"').to_lowercase().as_str(),
+        "true" | "yes"
+    )
+}
+
+/// Why an item is left out of the published site. `server --drafts` still shows
+/// it, badged with this.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unpublished {
+    Hidden,
+    Draft,
+    /// `publish_after` is still in the future.
+    Scheduled,
+}
+
+impl Unpublished {
+    /// Why the item is left out, if it is. A `publish_after` that can't be read is an
+    /// error rather than a guess, which would either hide the item for good or
+    /// publish it early.
+    fn of(
+        meta: Option<&MetaMedia>,
+        sidecar: &Sidecar,
+        timezone: Timezone,
+    ) -> Result<Option<Unpublished>, String> {
+        if meta.is_some_and(|m| m.hidden) || sidecar.hidden {
+            return Ok(Some(Unpublished::Hidden));
+        }
+        if meta.is_some_and(|m| m.draft) || sidecar.draft {
+            return Ok(Some(Unpublished::Draft));
+        }
+        let Some(publish_after) = meta
+            .and_then(|m| m.publish_after.as_deref())
+            .or(sidecar.publish_after.as_deref())
+        else {
+            return Ok(None);
+        };
+        let (datetime, offset) = parse_datetime_arg(publish_after)?;
+        let scheduled = datetime
+            .and_local_timezone(offset.unwrap_or_else(|| timezone.offset_of_local(datetime)))
+            .single()
+            .is_none_or(|time| time > Utc::now());
+        Ok(scheduled.then_some(Unpublished::Scheduled))
+    }
 }
 
 impl WebsiteMedia {
-    pub fn from_path(path: &Path, datetime: Option<&str>) -> Option<Self> {
+    pub fn from_path(
+        path: &Path,
+        name: &str,
+        slug: &str,
+        meta: Option<&MetaMedia>,
+        images: &ImageSettings,
+        site_timezone: Timezone,
+        cache: &BuildCache,
+    ) -> Result<Option<Self>, WebsiteError> {
         if !path.is_file() {
-            return None;
+            return Ok(None);
         }
 
-        let extension = path
+        let Some(extension) = path
             .extension()
             .and_then(|ext| ext.to_str())
-            .map(|ext| ext.to_lowercase())?;
+            .map(|ext| ext.to_lowercase())
+        else {
+            return Ok(None);
+        };
 
         let is_supported = SUPPORTED_EXTENSIONS.contains(&extension.as_str());
 
         if !is_supported {
-            return None;
+            return Ok(None);
         }
 
-        let filename = path.file_name().and_then(|n| n.to_str())?.to_string();
+        let filename = name.to_string();
+        let album = name.rsplit_once('/').map(|(album, _)| album.to_string());
 
         let stem = path
             .file_stem()
@@ -54,135 +453,387 @@ impl WebsiteMedia {
             .unwrap_or("")
             .to_string();
 
+        // An entry's own offset wins, otherwise the site's applies at its local time
+        let (datetime, timezone) = match meta {
+            Some(meta) => (
+                meta.datetime.clone(),
+                meta.timezone
+                    .as_deref()
+                    .and_then(parse_offset)
+                    .map_or(site_timezone, Timezone::Fixed),
+            ),
+            None => (
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .map(|time| format_system_time(time, site_timezone))
+                    .unwrap_or_else(|_| "1970-01-01T00:00:00".to_string()),
+                site_timezone,
+            ),
+        };
+        let offset = NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%dT%H:%M:%S").map_or_else(
+            |_| timezone.offset_at(Utc::now()),
+            |local| timezone.offset_of_local(local),
+        );
+
         // Check for a sidecar .txt file next to the media file
-        let (title, description) = {
-            let txt_path = path.with_extension("txt");
-            if txt_path.is_file() {
-                let content = fs::read_to_string(&txt_path).unwrap_or_default();
-                let lines: Vec<&str> = content.lines().collect();
-                if lines.len() >= 2 {
-                    let title = lines[0].trim().to_string();
-                    let description = lines[1..].join("\n").trim().to_string();
-                    (title, description)
-                } else if lines.len() == 1 {
-                    (stem.clone(), lines[0].trim().to_string())
+        let sidecar = Sidecar::read(&path.with_extension("txt"));
+        let unpublished = Unpublished::of(meta, &sidecar, timezone)
+            .map_err(|e| WebsiteError::PublishAfter(path.to_path_buf(), e))?;
+
+        // Overrides set with `clutterlog edit` win over the sidecar
+        let title = meta
+            .and_then(|m| m.title.clone())
+            .or(sidecar.title)
+            .unwrap_or(stem);
+        let description = meta
+            .and_then(|m| m.description.clone())
+            .unwrap_or(sidecar.description);
+
+        // Tags can come from metamedia.toml and from the sidecar front matter
+        let mut tags: Vec<String> = Vec::new();
+        let meta_tags = meta.map(|m| m.tags.as_slice()).unwrap_or_default();
+        for tag in meta_tags.iter().chain(sidecar.tags.iter()) {
+            let tag = tag.trim();
+            if !tag.is_empty() && !tags.iter().any(|t| slugify(t) == slugify(tag)) {
+                tags.push(tag.to_string());
+            }
+        }
+
+        let animated = ANIMATED_EXTENSIONS.contains(&extension.as_str())
+            || (extension == "png" && is_apng(path));
+        let needs_conversion = CONVERTED_EXTENSIONS.contains(&extension.as_str());
+
+        let display_widths = if animated || extension == "svg" {
+            Vec::new()
+        } else {
+            // Photos that don't record their size in EXIF are measured by decoding
+            // them, which only happens for the ones ffmpeg decodes. The cache keeps
+            // the result, so that's once per source.
+            let hash = meta.map(|m| m.hash.as_str()).unwrap_or_default();
+            let width = oriented_width(path).or_else(|| {
+                if !FFMPEG_DECODED_EXTENSIONS.contains(&extension.as_str()) {
+                    return None;
+                }
+                let measure = || decode_with_ffmpeg(path).ok().map(|img| img.width());
+                if hash.is_empty() {
+                    measure()
                 } else {
-                    (stem.clone(), String::new())
+                    cache.measured_width(hash, measure)
                 }
+            });
+            if needs_conversion && images.display_sizes.is_empty() {
+                // The original can't be shown as-is, publish a single full-size copy
+                width.into_iter().collect()
             } else {
-                (stem.clone(), String::new())
+                display_widths(width, &images.display_sizes)
             }
         };
+        // Sources browsers can't show are never published as the item's image
+        let publish_original =
+            images.keep_original || (display_widths.is_empty() && !needs_conversion);
 
-        let datetime = match datetime {
-            Some(dt) => dt.to_string(),
-            None => fs::metadata(path)
-                .and_then(|m| m.modified())
-                .map(format_system_time)
-                .unwrap_or_else(|_| "1970-01-01T00:00:00".to_string()),
-        };
-
-        Some(Self {
+        Ok(Some(Self {
             filename,
+            album,
+            slug: slug.to_string(),
             title,
             description,
+            tags,
             datetime,
+            offset,
+            unpublished,
             extension,
             source_path: path.to_path_buf(),
-        })
+            animated,
+            display_widths,
+            publish_original,
+            images: images.clone(),
+        }))
     }
 
     fn is_animated(&self) -> bool {
-        ANIMATED_EXTENSIONS.contains(&self.extension.as_str())
+        self.animated
     }
 
-    pub fn thumb_filename(&self) -> String {
-        let stem = Path::new(&self.filename)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or(&self.filename);
-        if self.is_animated() {
-            format!("{}_thumb.webp", stem)
+    fn is_video(&self) -> bool {
+        VIDEO_EXTENSIONS.contains(&self.extension.as_str())
+    }
+
+    fn is_vector(&self) -> bool {
+        self.extension == "svg"
+    }
+
+    /// Images in a format browsers can't show, published only as display copies.
+    fn is_converted_image(&self) -> bool {
+        !self.is_video() && CONVERTED_EXTENSIONS.contains(&self.extension.as_str())
+    }
+
+    /// Videos in a container browsers can't play are published as an MP4 copy.
+    fn is_transcoded(&self) -> bool {
+        self.is_video() && CONVERTED_EXTENSIONS.contains(&self.extension.as_str())
+    }
+
+    /// The file served when the item has no display copies: the source itself, or
+    /// its MP4 transcode.
+    fn published_filename(&self) -> String {
+        if self.is_transcoded() {
+            self.output_filename(format!("{}.mp4", self.base_name()))
         } else {
-            format!("{}_thumb.{}", stem, self.extension)
+            self.filename.clone()
         }
     }
 
-    /// Check whether the processed output (copied media + thumbnail) in `dest_media`
-    /// is already up to date with respect to the source media file.
-    /// Returns `true` if both output files exist and the thumbnail is newer than the source.
-    pub fn is_up_to_date(&self, dest_media: &Path) -> bool {
-        let dest_file = dest_media.join(&self.filename);
-        let thumb_file = dest_media.join(self.thumb_filename());
+    fn published_extension(&self) -> &str {
+        if self.is_transcoded() {
+            "mp4"
+        } else {
+            &self.extension
+        }
+    }
 
-        let thumb_mtime = match fs::metadata(&thumb_file).and_then(|m| m.modified()) {
-            Ok(t) => t,
-            Err(_) => return false,
-        };
+    /// Places a generated file next to the item's own path (inside its album).
+    fn output_filename(&self, file_name: String) -> String {
+        match &self.album {
+            Some(album) => format!("{}/{}", album, file_name),
+            None => file_name,
+        }
+    }
 
-        let dest_mtime = match fs::metadata(&dest_file).and_then(|m| m.modified()) {
-            Ok(t) => t,
-            Err(_) => return false,
-        };
+    /// What generated files are named after: the source's whole file name, so
+    /// `sketch.png` and `sketch.jpg` in one album don't write the same
+    /// `sketch_thumb.webp` or `sketch_1280.jpg`, nor `clip.mov` the same MP4 as
+    /// `clip.mp4`.
+    fn base_name(&self) -> &str {
+        Path::new(&self.filename)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(&self.filename)
+    }
 
-        let source_mtime = match fs::metadata(&self.source_path).and_then(|m| m.modified()) {
-            Ok(t) => t,
-            Err(_) => return false,
-        };
+    fn display_encodings(&self) -> Encodings {
+        Encodings::new(
+            self.images.display_format.image_format(&self.extension),
+            &self.extension,
+        )
+    }
+
+    fn thumb_encodings(&self) -> Encodings {
+        Encodings::new(
+            self.images.thumb_format.image_format(&self.extension),
+            &self.extension,
+        )
+    }
+
+    /// Thumbnail shown by `<img>`. Animated media always gets an animated WebP, and
+    /// SVGs scale well enough to be their own thumbnail.
+    pub fn thumb_filename(&self) -> String {
+        if self.is_vector() {
+            self.filename.clone()
+        } else if self.is_animated() {
+            self.output_filename(format!("{}_thumb.webp", self.base_name()))
+        } else {
+            self.thumb_filename_as(self.thumb_encodings().fallback)
+        }
+    }
 
-        thumb_mtime >= source_mtime && dest_mtime >= source_mtime
+    fn thumb_filename_as(&self, format: ImageFormat) -> String {
+        self.output_filename(format!(
+            "{}_thumb.{}",
+            self.base_name(),
+            extension_of(format)
+        ))
     }
 
-    /// Read file sizes from already-processed output files without regenerating them.
-    pub fn read_existing_sizes(&self, dest_media: &Path) -> Result<GenerationResult, WebsiteError> {
-        let dest_file = dest_media.join(&self.filename);
-        let thumb_file = dest_media.join(self.thumb_filename());
+    pub fn display_filename(&self, width: u32, format: ImageFormat) -> String {
+        self.output_filename(format!(
+            "{}_{}.{}",
+            self.base_name(),
+            width,
+            extension_of(format)
+        ))
+    }
 
-        let media_size = fs::metadata(&dest_file)
-            .map_err(|e| WebsiteError::Io(dest_file, e))?
-            .len();
-        let thumb_size = fs::metadata(&thumb_file)
-            .map_err(|e| WebsiteError::Io(thumb_file, e))?
-            .len();
+    /// Every file the item publishes into the media directory, and how each one is
+    /// made.
+    fn derivatives(&self) -> Vec<Derivative> {
+        let mut derivatives: Vec<Derivative> = Vec::new();
+        let encodings = self.display_encodings();
+        for &width in &self.display_widths {
+            for format in encodings.all() {
+                derivatives.push(Derivative {
+                    filename: self.display_filename(width, format),
+                    recipe: Recipe::Display { width, format },
+                });
+            }
+        }
+        if self.is_transcoded() {
+            derivatives.push(Derivative {
+                filename: self.published_filename(),
+                recipe: Recipe::Transcode,
+            });
+        }
+        if self.publish_original {
+            derivatives.push(Derivative {
+                filename: self.filename.clone(),
+                recipe: Recipe::Original,
+            });
+        }
 
-        Ok(GenerationResult {
-            media_size,
-            thumb_size,
-            image_url: String::new(),
-        })
+        if self.is_vector() {
+            // Served as-is, the original doubles as the thumbnail
+        } else if self.is_animated() {
+            derivatives.push(Derivative {
+                filename: self.thumb_filename(),
+                recipe: Recipe::AnimatedThumb,
+            });
+        } else {
+            for format in self.thumb_encodings().all() {
+                derivatives.push(Derivative {
+                    filename: self.thumb_filename_as(format),
+                    recipe: Recipe::Thumb { format },
+                });
+            }
+        }
+        derivatives
     }
 
-    pub fn copy_and_generate_thumb(
+    /// Publishes the item into `dest_media`: resized display copies (or the original
+    /// file) plus the grid thumbnail. Derivatives are taken from `cache`, generating
+    /// only the ones it doesn't hold yet, and files `previous` says were already
+    /// published from the same derivative are left alone. The flag is `true` when
+    /// nothing had to be generated.
+    pub fn publish(
         &self,
         dest_media: &Path,
-    ) -> Result<GenerationResult, WebsiteError> {
-        let dest_file = dest_media.join(&self.filename);
-        fs::copy(&self.source_path, &dest_file)
-            .map_err(|e| WebsiteError::Io(dest_file.clone(), e))?;
+        media_dir: &str,
+        cache: &BuildCache,
+        source_hash: &str,
+        previous: &BuildManifest,
+    ) -> Result<(GenerationResult, bool), WebsiteError> {
+        if self.display_widths.is_empty() && self.is_converted_image() {
+            // Its size couldn't be read, report why decoding it fails
+            self.load_image()?;
+            return Err(WebsiteError::Decode(
+                self.source_path.clone(),
+                "couldn't read the image size".to_string(),
+            ));
+        }
 
-        let thumb_path = dest_media.join(self.thumb_filename());
+        let derivatives: Vec<(Derivative, String, PathBuf)> = self
+            .derivatives()
+            .into_iter()
+            .map(|derivative| {
+                let key = derivative_key(source_hash, &derivative.recipe.params(&self.images));
+                let cached = match derivative.recipe {
+                    // Originals are served straight from the source, never cached
+                    Recipe::Original => self.source_path.clone(),
+                    _ => cache.path(source_hash, &key, extension_of_name(&derivative.filename)),
+                };
+                (derivative, key, cached)
+            })
+            .collect();
 
-        if self.is_animated() {
-            self.generate_ffmpeg_thumb(&thumb_path)?;
-        } else {
-            self.generate_image_thumb(&thumb_path)?;
+        let missing: Vec<(&Recipe, &Path)> = derivatives
+            .iter()
+            .filter(|(_, _, cached)| !cached.is_file())
+            .map(|(derivative, _, cached)| (&derivative.recipe, cached.as_path()))
+            .collect();
+        let up_to_date = missing.is_empty();
+        self.generate(&missing)?;
+
+        let mut result = GenerationResult {
+            media_size: 0,
+            thumb_size: 0,
+            image_url: String::new(), // filled in by scan_and_copy_media
+            image_size: 0,
+            files: Vec::new(),
+            cached: Vec::new(),
+        };
+        let image_filename = self.image_filename();
+        for (derivative, key, cached) in derivatives {
+            let dest = dest_media.join(&derivative.filename);
+            let published = format!("{}/{}", media_dir, derivative.filename);
+            if !dest.is_file() || previous.key(&published) != Some(key.as_str()) {
+                link_or_copy(&cached, &dest)?;
+            }
+
+            let size = fs::metadata(&cached)
+                .map_err(|e| WebsiteError::Io(cached.clone(), e))?
+                .len();
+            if derivative.recipe.is_thumb() {
+                result.thumb_size += size;
+            } else {
+                result.media_size += size;
+            }
+            if derivative.filename == image_filename {
+                result.image_size = size;
+            }
+            if !matches!(derivative.recipe, Recipe::Original) {
+                result.cached.push(cached);
+            }
+            result.files.push((derivative.filename, key));
         }
 
-        let media_size = fs::metadata(&dest_file)
-            .map_err(|e| WebsiteError::Io(dest_file.clone(), e))?
-            .len();
-        let thumb_size = fs::metadata(&thumb_path)
-            .map_err(|e| WebsiteError::Io(thumb_path.clone(), e))?
-            .len();
+        Ok((result, up_to_date))
+    }
 
-        Ok(GenerationResult {
-            media_size,
-            thumb_size,
-            image_url: String::new(), // filled in by scan_and_copy_media
-        })
+    /// Generates the `missing` derivatives at their cache paths. The source image is
+    /// decoded at most once.
+    fn generate(&self, missing: &[(&Recipe, &Path)]) -> Result<(), WebsiteError> {
+        let needs_image = missing
+            .iter()
+            .any(|(recipe, _)| matches!(recipe, Recipe::Display { .. } | Recipe::Thumb { .. }));
+        let image = if needs_image {
+            Some(self.load_image()?)
+        } else {
+            None
+        };
+        let mut thumb: Option<DynamicImage> = None;
+        let mut resized: Option<(u32, DynamicImage)> = None;
+
+        for &(recipe, path) in missing {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| WebsiteError::Io(parent.to_path_buf(), e))?;
+            }
+            let partial = partial_path(path);
+
+            match (*recipe, &image) {
+                (Recipe::Transcode, _) => self.transcode_video(&partial)?,
+                (Recipe::AnimatedThumb, _) => self.generate_ffmpeg_thumb(&partial)?,
+                (Recipe::Display { width, format }, Some(img)) => {
+                    if resized.as_ref().is_none_or(|(w, _)| *w != width) {
+                        let copy = if width >= img.width() {
+                            img.clone()
+                        } else {
+                            img.resize(width, u32::MAX, FilterType::Lanczos3)
+                        };
+                        resized = Some((width, copy));
+                    }
+                    if let Some((_, copy)) = &resized {
+                        save_image(copy, &partial, format, self.images.display_quality)?;
+                    }
+                }
+                (Recipe::Thumb { format }, Some(img)) => {
+                    let thumb = thumb.get_or_insert_with(|| center_crop_resize(img, THUMB_SIZE));
+                    save_image(thumb, &partial, format, self.images.thumb_quality)?;
+                }
+                // Originals are never generated
+                _ => continue,
+            }
+
+            fs::rename(&partial, path).map_err(|e| WebsiteError::Io(path.to_path_buf(), e))?;
+        }
+
+        Ok(())
     }
 
-    fn generate_image_thumb(&self, thumb_path: &Path) -> Result<(), WebsiteError> {
+    /// Decodes the source image with its EXIF orientation applied.
+    fn load_image(&self) -> Result<DynamicImage, WebsiteError> {
+        if FFMPEG_DECODED_EXTENSIONS.contains(&self.extension.as_str()) {
+            return self.load_image_with_ffmpeg();
+        }
+
         let mut decoder = ImageReader::open(&self.source_path)
             .map_err(|e| WebsiteError::Io(self.source_path.clone(), e))?
             .into_decoder()
@@ -193,94 +844,212 @@ impl WebsiteMedia {
         let mut img = DynamicImage::from_decoder(decoder)
             .map_err(|e| WebsiteError::Image(self.source_path.clone(), e))?;
         img.apply_orientation(orientation);
+        Ok(img)
+    }
 
-        let thumb = center_crop_resize(&img, THUMB_SIZE);
-
-        let format = match self.extension.as_str() {
-            "jpg" | "jpeg" => ImageFormat::Jpeg,
-            "webp" => ImageFormat::WebP,
-            _ => ImageFormat::Jpeg,
-        };
-
-        thumb
-            .save_with_format(thumb_path, format)
-            .map_err(|e| WebsiteError::Image(thumb_path.to_path_buf(), e))?;
+    /// Decodes formats the `image` crate doesn't support (HEIC, AVIF) with ffmpeg.
+    /// Fails if ffmpeg returns less of the picture than EXIF says it holds: before
+    /// 7.1, ffmpeg only decodes the first tile of tiled HEIC photos (iPhones).
+    fn load_image_with_ffmpeg(&self) -> Result<DynamicImage, WebsiteError> {
+        let img = decode_with_ffmpeg(&self.source_path)?;
+        if let Some((width, height)) = exif_oriented_size(&self.source_path)
+            && u64::from(img.width()) * u64::from(img.height())
+                < u64::from(width) * u64::from(height)
+        {
+            return Err(WebsiteError::Decode(
+                self.source_path.clone(),
+                format!(
+                    "ffmpeg decoded {}x{} pixels of a {}x{} image, tiled HEIC photos need ffmpeg 7.1 or later",
+                    img.width(),
+                    img.height(),
+                    width,
+                    height
+                ),
+            ));
+        }
+        Ok(img)
+    }
 
+    /// Re-encodes a MOV/MKV source as an H.264/AAC MP4 every browser can play.
+    fn transcode_video(&self, dest: &Path) -> Result<(), WebsiteError> {
+        run_ffmpeg(
+            &self.source_path,
+            &["-i", self.source_path.to_str().unwrap_or("")]
+                .into_iter()
+                .chain(TRANSCODE_ARGS.iter().copied())
+                .chain(["-y", dest.to_str().unwrap_or("")])
+                .collect::<Vec<_>>(),
+        )?;
         Ok(())
     }
 
     /// Generate an animated thumbnail for a gif/video file (gif, webm, mp4).
     /// Uses ffmpeg to produce a center-cropped 350x350 animated WebP of max 2 seconds.
     fn generate_ffmpeg_thumb(&self, thumb_path: &Path) -> Result<(), WebsiteError> {
-        let filter = format!(
-            "crop=min(iw\\,ih):min(iw\\,ih):(iw-min(iw\\,ih))/2:(ih-min(iw\\,ih))/2,scale={}:{}",
-            THUMB_SIZE, THUMB_SIZE
-        );
+        let filter = animated_thumb_filter();
 
-        let output = Command::new("ffmpeg")
-            .args([
-                "-i",
-                self.source_path.to_str().unwrap_or(""),
-                "-t",
-                "2",
-                "-vf",
-                &filter,
-                "-c:v",
-                "libwebp_anim",
-                "-loop",
-                "0",
-                "-an",
-                "-y",
-                thumb_path.to_str().unwrap_or(""),
-            ])
-            .output()
-            .map_err(|e: io::Error| {
-                if e.kind() == io::ErrorKind::NotFound {
-                    WebsiteError::FfmpegNotFound(e.to_string())
-                } else {
-                    WebsiteError::Ffmpeg(self.source_path.clone(), e.to_string())
-                }
-            })?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(WebsiteError::Ffmpeg(
-                self.source_path.clone(),
-                format!("ffmpeg exited with {}: {}", output.status, stderr),
-            ));
+        // The PNG image decoder only sees the first frame of an APNG
+        let mut args = Vec::new();
+        if matches!(self.extension.as_str(), "png" | "apng") {
+            args.extend(["-f", "apng"]);
         }
+        args.extend([
+            "-i",
+            self.source_path.to_str().unwrap_or(""),
+            "-vf",
+            &filter,
+        ]);
+        args.extend(ANIMATED_THUMB_ARGS);
+        args.extend(["-y", thumb_path.to_str().unwrap_or("")]);
 
+        run_ffmpeg(&self.source_path, &args)?;
         Ok(())
     }
 
-    pub fn to_json_entry(&self, base_url: &str, media_dir: &str) -> String {
-        let image_url = format!("{}/{}/{}", base_url, media_dir, self.filename);
-        let thumb_url = format!("{}/{}/{}", base_url, media_dir, self.thumb_filename());
+    /// The capture time with its offset, `None` if the stored date doesn't parse.
+    fn captured_at(&self) -> Option<DateTime<FixedOffset>> {
+        NaiveDateTime::parse_from_str(&self.datetime, "%Y-%m-%dT%H:%M:%S")
+            .ok()?
+            .and_local_timezone(self.offset)
+            .single()
+    }
 
-        format!(
-            "            {{ \"image_url\": \"{}\", \"thumb_url\": \"{}\", \"title\": \"{}\", \"description\": \"{}\", \"datetime\": \"{}\" }}",
-            escape_js(&image_url),
-            escape_js(&thumb_url),
-            escape_js(&self.title),
-            escape_js(&self.description),
-            escape_js(&self.datetime),
-        )
+    fn timestamp(&self) -> i64 {
+        self.captured_at().map_or(0, |dt| dt.timestamp())
     }
 
+    pub fn to_item(&self, base_url: &str, media_dir: &str, pages_dir: &str) -> MediaItem {
+        MediaItem {
+            name: self.filename.clone(),
+            album: self.album.clone(),
+            tags: self.tags.clone(),
+            tag_slugs: self.tags.iter().map(|t| slugify(t)).collect(),
+            url: self.page_url(base_url, pages_dir),
+            image_url: self.image_url(base_url, media_dir),
+            srcset: self.srcset(base_url, media_dir),
+            sources: self.sources(base_url, media_dir),
+            original_url: self.original_url(base_url, media_dir),
+            thumb_url: self.thumb_url(base_url, media_dir),
+            thumb_sources: self.thumb_sources(base_url, media_dir),
+            title: self.title.clone(),
+            description: self.description.clone(),
+            datetime: self.datetime.clone(),
+            timestamp: self.timestamp(),
+            unpublished: self.unpublished,
+        }
+    }
+
+    /// URL of the file shown in the lightbox: the largest display copy, or the
+    /// original for media that isn't resized.
     pub fn image_url(&self, base_url: &str, media_dir: &str) -> String {
-        format!("{}/{}/{}", base_url, media_dir, self.filename)
+        media_url(base_url, media_dir, &self.image_filename())
+    }
+
+    /// The largest display copy, or the published file if there are none.
+    fn image_filename(&self) -> String {
+        match self.display_widths.last() {
+            Some(&width) => self.display_filename(width, self.display_encodings().fallback),
+            None => self.published_filename(),
+        }
+    }
+
+    /// MIME type of the file behind `image_url`.
+    fn image_mime_type(&self) -> &'static str {
+        if self.display_widths.is_empty() {
+            mime_type(self.published_extension())
+        } else {
+            self.display_encodings().fallback.to_mime_type()
+        }
     }
 
-    pub fn to_rss_item(&self, base_url: &str, media_dir: &str) -> String {
+    pub fn original_url(&self, base_url: &str, media_dir: &str) -> Option<String> {
+        self.publish_original
+            .then(|| media_url(base_url, media_dir, &self.filename))
+    }
+
+    /// `srcset` attribute value listing every display copy, empty if there are none.
+    pub fn srcset(&self, base_url: &str, media_dir: &str) -> String {
+        self.srcset_as(base_url, media_dir, self.display_encodings().fallback)
+    }
+
+    fn srcset_as(&self, base_url: &str, media_dir: &str, format: ImageFormat) -> String {
+        self.display_widths
+            .iter()
+            .map(|&width| {
+                format!(
+                    "{} {}w",
+                    media_url(base_url, media_dir, &self.display_filename(width, format)),
+                    width
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Modern-format alternatives to `srcset`, for `<picture>` sources.
+    pub fn sources(&self, base_url: &str, media_dir: &str) -> Vec<ImageSource> {
+        match self.display_encodings().modern {
+            Some(format) if !self.display_widths.is_empty() => vec![ImageSource {
+                mime_type: format.to_mime_type(),
+                srcset: self.srcset_as(base_url, media_dir, format),
+            }],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn thumb_url(&self, base_url: &str, media_dir: &str) -> String {
+        media_url(base_url, media_dir, &self.thumb_filename())
+    }
+
+    /// Modern-format alternatives to `thumb_url`, for `<picture>` sources.
+    pub fn thumb_sources(&self, base_url: &str, media_dir: &str) -> Vec<ImageSource> {
+        match self.thumb_encodings().modern {
+            Some(format) if !self.is_animated() => vec![ImageSource {
+                mime_type: format.to_mime_type(),
+                srcset: media_url(base_url, media_dir, &self.thumb_filename_as(format)),
+            }],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn page_url(&self, base_url: &str, pages_dir: &str) -> String {
+        format!("{}/{}/{}/", base_url, pages_dir, encode_path(&self.slug))
+    }
+
+    pub fn to_page(&self, base_url: &str, media_dir: &str, pages_dir: &str) -> ItemPage {
+        ItemPage {
+            name: self.filename.clone(),
+            slug: self.slug.clone(),
+            url: self.page_url(base_url, pages_dir),
+            title: self.title.clone(),
+            description: self.description.clone(),
+            datetime: self
+                .captured_at()
+                .map(|dt| dt.to_rfc3339())
+                .unwrap_or_else(|| self.datetime.clone()),
+            timestamp: self.timestamp(),
+            display_date: datetime_to_display(&self.datetime),
+            image_url: self.image_url(base_url, media_dir),
+            srcset: self.srcset(base_url, media_dir),
+            sources: self.sources(base_url, media_dir),
+            original_url: self.original_url(base_url, media_dir),
+            thumb_url: self.thumb_url(base_url, media_dir),
+            thumb_sources: self.thumb_sources(base_url, media_dir),
+            mime_type: self.image_mime_type(),
+            is_video: self.is_video(),
+            album: self.album.clone(),
+            tags: self.tags.clone(),
+            unpublished: self.unpublished,
+        }
+    }
+
+    pub fn to_feed_item(&self, base_url: &str, media_dir: &str, pages_dir: &str) -> FeedItem {
         let base_url = base_url.trim_end_matches('/');
         let image_url = self.image_url(base_url, media_dir);
-        let item_link = format!("{}/#media={}", base_url, self.filename);
-        let pub_date = datetime_to_rfc2822(&self.datetime);
-        let mime = mime_type(&self.extension);
+        let item_link = self.page_url(base_url, pages_dir);
         let description = self.description.as_str();
 
-        let media_html = if self.is_animated() && matches!(self.extension.as_str(), "webm" | "mp4")
-        {
+        let media_html = if self.is_video() {
             format!("<video src=\"{}\" controls></video>", image_url)
         } else {
             format!(
@@ -290,56 +1059,320 @@ impl WebsiteMedia {
             )
         };
 
-        let html_content = format!(
-            "<![CDATA[<h2>{}</h2>{}<p>{}</p>]]>",
+        let content = format!(
+            "<h2>{}</h2>{}<p>{}</p>",
             escape_html(&self.title),
             media_html,
             escape_html(description),
         );
 
-        format!(
-            "        <item>\n            <title>{}</title>\n            <link>{}</link>\n            <guid>{}</guid>\n            <pubDate>{}</pubDate>\n            <enclosure url=\"{}\" type=\"{}\" length=\"0\"/>\n            <description>{}</description>\n        </item>",
-            escape_xml(&self.title),
-            escape_xml(&item_link),
-            escape_xml(&image_url),
-            pub_date,
-            escape_xml(&image_url),
-            mime,
-            html_content,
-        )
+        let captured_at = self.captured_at();
+        FeedItem {
+            title: self.title.clone(),
+            link: item_link,
+            guid: image_url.clone(),
+            pub_date: captured_at
+                .map(|dt| dt.format("%a, %d %b %Y %H:%M:%S %z").to_string())
+                .unwrap_or_else(|| self.datetime.clone()),
+            published: captured_at
+                .map(|dt| dt.to_rfc3339())
+                .unwrap_or_else(|| self.datetime.clone()),
+            enclosure_url: image_url,
+            enclosure_length: 0, // filled in by scan_and_copy_media
+            mime_type: self.image_mime_type(),
+            thumb_url: self.thumb_url(base_url, media_dir),
+            album: self.album.clone(),
+            tags: self.tags.clone(),
+            description: self.description.clone(),
+            content,
+        }
+    }
+}
+
+/// URL of a file published into the media directory.
+fn media_url(base_url: &str, media_dir: &str, filename: &str) -> String {
+    format!("{}/{}/{}", base_url, media_dir, encode_path(filename))
+}
+
+/// Has ffmpeg convert the first frame of `path` to PNG and decodes that. ffmpeg
+/// already applies the container's rotation, so no extra orientation handling is
+/// needed.
+fn decode_with_ffmpeg(path: &Path) -> Result<DynamicImage, WebsiteError> {
+    let output = run_ffmpeg(
+        path,
+        &[
+            "-i",
+            path.to_str().unwrap_or(""),
+            "-frames:v",
+            "1",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "png",
+            "-",
+        ],
+    )?;
+
+    image::load_from_memory_with_format(&output, ImageFormat::Png)
+        .map_err(|e| WebsiteError::Image(path.to_path_buf(), e))
+}
+
+/// Runs ffmpeg on `source` and returns its stdout.
+fn run_ffmpeg(source: &Path, args: &[&str]) -> Result<Vec<u8>, WebsiteError> {
+    let output = Command::new("ffmpeg")
+        .args(args)
+        .output()
+        .map_err(|e: io::Error| {
+            if e.kind() == io::ErrorKind::NotFound {
+                WebsiteError::FfmpegNotFound(e.to_string())
+            } else {
+                WebsiteError::Ffmpeg(source.to_path_buf(), e.to_string())
+            }
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(WebsiteError::Ffmpeg(
+            source.to_path_buf(),
+            format!("ffmpeg exited with {}: {}", output.status, stderr),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Returns `true` if the PNG at `path` is animated, i.e. has an `acTL` chunk
+/// before its first `IDAT`.
+fn is_apng(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let mut reader = io::BufReader::new(file);
+
+    let mut signature = [0u8; 8];
+    if reader.read_exact(&mut signature).is_err() || &signature != b"\x89PNG\r\n\x1a\n" {
+        return false;
+    }
+
+    let mut header = [0u8; 8];
+    while reader.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match &header[4..] {
+            b"acTL" => return true,
+            b"IDAT" | b"IEND" => return false,
+            // Skip the chunk data and its CRC
+            _ => {
+                if reader.seek_relative(i64::from(length) + 4).is_err() {
+                    return false;
+                }
+            }
+        }
     }
+    false
+}
+
+/// Encodes `img` into `path`, honouring `quality` for the lossy encoders.
+fn save_image(
+    img: &DynamicImage,
+    path: &Path,
+    format: ImageFormat,
+    quality: u8,
+) -> Result<(), WebsiteError> {
+    let quality = quality.clamp(1, 100);
+    if format == ImageFormat::WebP {
+        return save_webp(img, path, quality);
+    }
+    let file = File::create(path).map_err(|e| WebsiteError::Io(path.to_path_buf(), e))?;
+    let mut writer = BufWriter::new(file);
+
+    let result = match format {
+        // JPEG has no alpha channel
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut writer, quality)),
+        ImageFormat::Avif => img.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            &mut writer,
+            AVIF_SPEED,
+            quality,
+        )),
+        _ => img.write_to(&mut writer, format),
+    };
+
+    result.map_err(|e| WebsiteError::Image(path.to_path_buf(), e))?;
+    writer
+        .flush()
+        .map_err(|e| WebsiteError::Io(path.to_path_buf(), e))
+}
+
+/// Encodes lossy WebP with ffmpeg's libwebp. The only WebP encoder `image` ships
+/// is lossless, and its output is often larger than the JPEG it stands in for.
+fn save_webp(img: &DynamicImage, path: &Path, quality: u8) -> Result<(), WebsiteError> {
+    let png = path.with_extension("png");
+    img.save_with_format(&png, ImageFormat::Png)
+        .map_err(|e| WebsiteError::Image(png.clone(), e))?;
+    let quality = quality.to_string();
+    let result = run_ffmpeg(
+        path,
+        &[
+            "-v",
+            "error",
+            "-i",
+            png.to_str().unwrap_or(""),
+            "-c:v",
+            "libwebp",
+            "-lossless",
+            "0",
+            "-quality",
+            &quality,
+            "-f",
+            "webp",
+            "-y",
+            path.to_str().unwrap_or(""),
+        ],
+    );
+    let _ = fs::remove_file(&png);
+    result.map(|_| ())
+}
+
+fn extension_of(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("bin")
+}
+
+fn extension_of_name(filename: &str) -> &str {
+    filename
+        .rsplit_once('.')
+        .map_or("", |(_, extension)| extension)
+}
+
+/// Center-crops and scales to a `THUMB_SIZE` square.
+fn animated_thumb_filter() -> String {
+    format!(
+        "crop=min(iw\\,ih):min(iw\\,ih):(iw-min(iw\\,ih))/2:(ih-min(iw\\,ih))/2,scale={}:{}",
+        THUMB_SIZE, THUMB_SIZE
+    )
+}
+
+/// Encoder settings that end up in a derivative's cache key.
+fn encoding_params(format: ImageFormat, quality: u8) -> String {
+    match format {
+        ImageFormat::Jpeg => format!("jpg q{}", quality),
+        ImageFormat::Avif => format!("avif q{} speed{}", quality, AVIF_SPEED),
+        ImageFormat::WebP => format!("webp q{} libwebp", quality),
+        _ => extension_of(format).to_string(),
+    }
+}
+
+/// Places `source` at `dest`, hard-linking when possible since build outputs never
+/// change in place.
+fn link_or_copy(source: &Path, dest: &Path) -> Result<(), WebsiteError> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|e| WebsiteError::Io(parent.to_path_buf(), e))?;
+    }
+    match fs::remove_file(dest) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(WebsiteError::Io(dest.to_path_buf(), e)),
+    }
+    if fs::hard_link(source, dest).is_err() {
+        fs::copy(source, dest).map_err(|e| WebsiteError::Io(dest.to_path_buf(), e))?;
+    }
+    Ok(())
+}
+
+/// Picks the display widths for an image `width` pixels wide (orientation
+/// corrected): every configured size narrower than it, or the width itself if
+/// none is.
+fn display_widths(width: Option<u32>, sizes: &[u32]) -> Vec<u32> {
+    if sizes.is_empty() {
+        return Vec::new();
+    }
+    let Some(width) = width else {
+        return Vec::new();
+    };
+
+    let mut widths: Vec<u32> = sizes
+        .iter()
+        .copied()
+        .filter(|&w| w > 0 && w < width)
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+    if widths.is_empty() {
+        widths.push(width);
+    }
+    widths
+}
+
+/// Reads the image width from the file header, swapped with the height when the
+/// EXIF orientation rotates the image by 90 degrees.
+fn oriented_width(path: &Path) -> Option<u32> {
+    let Some(mut decoder) = ImageReader::open(path)
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+    else {
+        return exif_oriented_size(path).map(|(width, _)| width);
+    };
+    let (width, height) = decoder.dimensions();
+    let rotated = matches!(
+        decoder.orientation().ok()?,
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    );
+    Some(if rotated { height } else { width })
+}
+
+/// The width and height of an image as cameras record them in EXIF, swapped when
+/// the orientation rotates it. For formats the `image` crate can't open (HEIC,
+/// AVIF).
+fn exif_oriented_size(path: &Path) -> Option<(u32, u32)> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut io::BufReader::new(file))
+        .ok()?;
+    let dimension = |tag| {
+        exif.get_field(tag, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+    };
+
+    let width = dimension(exif::Tag::PixelXDimension)?;
+    let height = dimension(exif::Tag::PixelYDimension)?;
+    // Orientations 5-8 rotate by 90 degrees
+    let rotated = dimension(exif::Tag::Orientation).is_some_and(|o| (5..=8).contains(&o));
+    Some(if rotated {
+        (height, width)
+    } else {
+        (width, height)
+    })
 }
 
 fn mime_type(extension: &str) -> &'static str {
     match extension {
         "jpg" | "jpeg" => "image/jpeg",
         "png" => "image/png",
+        "apng" => "image/apng",
         "webp" => "image/webp",
         "gif" => "image/gif",
+        "avif" => "image/avif",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "tif" | "tiff" => "image/tiff",
+        "svg" => "image/svg+xml",
         "webm" => "video/webm",
         "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
         _ => "application/octet-stream",
     }
 }
 
-fn datetime_to_rfc2822(datetime: &str) -> String {
+fn datetime_to_display(datetime: &str) -> String {
     use chrono::NaiveDateTime;
     NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S")
-        .map(|ndt| {
-            let dt = ndt.and_utc();
-            dt.format("%a, %d %b %Y %H:%M:%S +0000").to_string()
-        })
+        .map(|ndt| ndt.format("%b %-d, %Y").to_string())
         .unwrap_or_else(|_| datetime.to_string())
 }
 
-fn escape_xml(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&apos;")
-}
-
 fn escape_html(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
@@ -364,14 +1397,29 @@ fn center_crop_resize(img: &image::DynamicImage, size: u32) -> image::DynamicIma
     cropped.resize_exact(size, size, FilterType::Lanczos3)
 }
 
-fn format_system_time(time: SystemTime) -> String {
+fn format_system_time(time: SystemTime, timezone: Timezone) -> String {
     let dt: DateTime<Utc> = time.into();
-    dt.format("%Y-%m-%dT%H:%M:%S").to_string()
+    dt.with_timezone(&timezone.offset_at(dt))
+        .format("%Y-%m-%dT%H:%M:%S")
+        .to_string()
 }
 
-fn escape_js(s: &str) -> String {
-    s.replace('\\', "\\\\")
-        .replace('"', "\\\"")
-        .replace('\n', "\\n")
-        .replace('\r', "\\r")
+/// Turns arbitrary text into a URL path segment: `Robot Arm/First Print` becomes
+/// `robot-arm-first-print`. Callers are responsible for resolving collisions.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "item".to_string()
+    } else {
+        slug.to_string()
+    }
 }
@@ -3,7 +3,13 @@
 (function () {
     "use strict";
 
+    var entries = [];
     var sorted = [];
+    var nextChunk = null;
+    var loadingChunk = false;
+    var sections = {};
+    var lastSection = null;
+    var activeTag = null;
     var currentIndex = -1;
     var zoomLevel = 1.0;
 
@@ -12,7 +18,6 @@
     var ZOOM_MIN = 0.5;
     var ZOOM_MAX = 5.0;
 
-    var ANIMATED_EXTENSIONS = ["gif"];
     var VIDEO_EXTENSIONS = ["mp4", "webm"];
 
     // --- Utility ---
@@ -31,22 +36,114 @@
         return parts.length > 1 ? parts[parts.length - 1].toLowerCase() : "";
     }
 
-    function getFilename(url) {
-        var parts = url.split("/");
-        return parts[parts.length - 1];
+    // Tags are matched by slug, the way the tag pages group them
+    function hasTag(entry, slug) {
+        return (entry.tag_slugs || []).indexOf(slug) !== -1;
+    }
+
+    // --- Data ---
+
+    // The page's chunk of items comes inline when the site sets `inline_data`,
+    // otherwise from the data.json file next to the page. Each chunk links to the
+    // next page's one.
+    function loadEntries(callback) {
+        if (typeof CLUTTERLOG_DATA !== "undefined") {
+            callback(resolveChunk(CLUTTERLOG_DATA, location.href));
+            return;
+        }
+
+        var grid = document.getElementById("grid");
+        var src = grid && grid.getAttribute("data-src");
+        if (!src) return;
+
+        fetchChunk(src, callback);
+    }
+
+    function fetchChunk(src, callback) {
+        fetch(src)
+            .then(function (response) {
+                if (!response.ok) throw new Error(response.status + " " + response.statusText);
+                return response.json().then(function (chunk) {
+                    return resolveChunk(chunk, response.url);
+                });
+            })
+            .then(callback)
+            .catch(function (err) {
+                console.error("clutterlog: failed to load " + src + ": " + err.message);
+            });
+    }
+
+    // Sites built with `relative_urls` link each chunk's items relative to the
+    // chunk's own page, which isn't necessarily the one showing them. Makes them
+    // absolute, resolved against the chunk.
+    function resolveChunk(chunk, chunkUrl) {
+        function resolve(url) {
+            return url ? new URL(url, chunkUrl).href : url;
+        }
+        function resolveSrcset(srcset) {
+            if (!srcset) return srcset;
+            return srcset.split(",").map(function (candidate) {
+                var parts = candidate.trim().split(/\s+/);
+                parts[0] = resolve(parts[0]);
+                return parts.join(" ");
+            }).join(", ");
+        }
+        function resolveSources(sources) {
+            (sources || []).forEach(function (source) {
+                source.srcset = resolveSrcset(source.srcset);
+            });
+        }
+
+        chunk.next = resolve(chunk.next);
+        (chunk.sections || []).forEach(function (section) {
+            section.url = resolve(section.url);
+        });
+        (chunk.items || []).forEach(function (item) {
+            item.url = resolve(item.url);
+            item.image_url = resolve(item.image_url);
+            item.original_url = resolve(item.original_url);
+            item.thumb_url = resolve(item.thumb_url);
+            item.srcset = resolveSrcset(item.srcset);
+            resolveSources(item.sources);
+            resolveSources(item.thumb_sources);
+        });
+        return chunk;
     }
 
     // --- Grid ---
 
+    // Replaces the grid (including the server-rendered fallback) with the loaded
+    // entries that pass the tag filter
     function buildGrid() {
         var grid = document.getElementById("grid");
-        if (!grid || typeof CLUTTERLOG_DATA === "undefined") return;
+        if (!grid) return;
 
-        sorted = CLUTTERLOG_DATA.slice().sort(function (a, b) {
-            return new Date(b.datetime) - new Date(a.datetime);
-        });
+        grid.innerHTML = "";
+        sorted = [];
+        lastSection = null;
+        appendToGrid(entries);
+    }
+
+    function appendToGrid(newEntries) {
+        var grid = document.getElementById("grid");
+        if (!grid) return;
+
+        newEntries.forEach(function (entry) {
+            if (activeTag !== null && !hasTag(entry, activeTag)) return;
+            var index = sorted.length;
+            sorted.push(entry);
+
+            // Start each month with a separator linking to its archive page
+            var key = entry.datetime.slice(0, 7);
+            if (key !== lastSection && sections[key]) {
+                lastSection = key;
+                var separator = document.createElement("a");
+                separator.className = "grid-section";
+                separator.href = sections[key].url;
+                separator.textContent = sections[key].title;
+                grid.appendChild(separator);
+            }
 
-        sorted.forEach(function (entry, index) {
             var item = document.createElement("div");
             item.className = "grid-item";
 
@@ -74,7 +171,13 @@
             overlay.appendChild(description);
             overlay.appendChild(date);
 
-            item.appendChild(img);
+            item.appendChild(withSources(img, entry.thumb_sources));
+            if (entry.unpublished) {
+                var badge = document.createElement("span");
+                badge.className = "badge";
+                badge.textContent = entry.unpublished;
+                item.appendChild(badge);
+            }
             item.appendChild(overlay);
             grid.appendChild(item);
 
@@ -84,6 +187,86 @@
         });
     }
 
+    function addSections(chunk) {
+        (chunk.sections || []).forEach(function (section) {
+            sections[section.key] = section;
+        });
+    }
+
+    // --- Infinite scroll ---
+
+    // Loads the next chunk when the end of the grid comes into view. The static
+    // page links are only needed without JavaScript.
+    function bindInfiniteScroll() {
+        var grid = document.getElementById("grid");
+        if (!grid || !("IntersectionObserver" in window)) return;
+
+        var pagination = document.getElementById("pagination");
+        if (pagination) pagination.hidden = true;
+
+        var sentinel = document.createElement("div");
+        sentinel.className = "grid-sentinel";
+        grid.parentNode.insertBefore(sentinel, grid.nextSibling);
+
+        var observer = new IntersectionObserver(function (observed) {
+            if (!observed[0].isIntersecting || !nextChunk || loadingChunk) return;
+
+            loadingChunk = true;
+            fetchChunk(nextChunk, function (chunk) {
+                loadingChunk = false;
+                addSections(chunk);
+                entries = entries.concat(chunk.items);
+                nextChunk = chunk.next;
+                appendToGrid(chunk.items);
+
+                // Observing again re-checks the sentinel, in case it's still in view
+                observer.unobserve(sentinel);
+                observer.observe(sentinel);
+            });
+        }, { rootMargin: "800px" });
+        observer.observe(sentinel);
+    }
+
+    // Wraps an <img> in a <picture> offering the modern-format alternatives, if any
+    function withSources(img, sources) {
+        if (img.tagName !== "IMG" || !sources || sources.length === 0) return img;
+
+        var picture = document.createElement("picture");
+        sources.forEach(function (source) {
+            var el = document.createElement("source");
+            el.type = source.mime_type;
+            el.srcset = source.srcset;
+            if (img.sizes) el.sizes = img.sizes;
+            picture.appendChild(el);
+        });
+        picture.appendChild(img);
+        return picture;
+    }
+
+    // --- Tag filter ---
+
+    function bindTagFilter() {
+        var container = document.getElementById("tags");
+        if (!container) return;
+
+        var links = container.querySelectorAll(".tag[data-tag]");
+        Array.prototype.forEach.call(links, function (link) {
+            link.addEventListener("click", function (e) {
+                // Let modified clicks open the tag page as usual
+                if (e.metaKey || e.ctrlKey || e.shiftKey || e.button !== 0) return;
+                e.preventDefault();
+
+                var tag = link.getAttribute("data-tag");
+                activeTag = activeTag === tag ? null : tag;
+
+                Array.prototype.forEach.call(links, function (other) {
+                    other.classList.toggle("active", other.getAttribute("data-tag") === activeTag);
+                });
+                buildGrid();
+            });
+        });
+    }
+
     // --- Lightbox ---
 
     function openLightbox(index) {
@@ -111,12 +294,16 @@
             mediaEl.playsInline = true;
         } else {
             mediaEl = document.createElement("img");
+            if (entry.srcset) {
+                mediaEl.srcset = entry.srcset;
+                mediaEl.sizes = "100vw";
+            }
             mediaEl.src = entry.image_url;
             mediaEl.alt = entry.description || entry.title;
         }
 
         mediaEl.id = "lightbox-media";
-        content.appendChild(mediaEl);
+        content.appendChild(withSources(mediaEl, entry.sources));
 
         info.innerHTML = "";
         var titleEl = document.createElement("div");
@@ -136,13 +323,28 @@
         dateEl.textContent = formatDate(entry.datetime);
         info.appendChild(dateEl);
 
+        if (entry.url) {
+            var linkEl = document.createElement("a");
+            linkEl.className = "lightbox-permalink";
+            linkEl.href = entry.url;
+            linkEl.textContent = "permalink";
+            info.appendChild(linkEl);
+        }
+
+        if (entry.original_url && entry.srcset) {
+            var originalEl = document.createElement("a");
+            originalEl.className = "lightbox-permalink";
+            originalEl.href = entry.original_url;
+            originalEl.textContent = "original";
+            info.appendChild(originalEl);
+        }
+
         lightbox.hidden = false;
         document.body.style.overflow = "hidden";
 
         applyZoom();
 
-        var filename = getFilename(entry.image_url);
-        history.replaceState(null, "", "#media=" + encodeURIComponent(filename));
+        history.replaceState(null, "", "#media=" + encodeURIComponent(entry.name));
     }
 
     function closeLightbox() {
@@ -207,7 +409,7 @@
         if (!filename) return;
 
         for (var i = 0; i < sorted.length; i++) {
-            if (getFilename(sorted[i].image_url) === filename) {
+            if (sorted[i].name === filename) {
                 openLightbox(i);
                 return;
             }
@@ -293,8 +495,15 @@
     // --- Init ---
 
     document.addEventListener("DOMContentLoaded", function () {
-        buildGrid();
+        bindTagFilter();
         bindEvents();
-        openFromHash();
+        loadEntries(function (chunk) {
+            addSections(chunk);
+            entries = chunk.items;
+            nextChunk = chunk.next;
+            buildGrid();
+            bindInfiniteScroll();
+            openFromHash();
+        });
     });
 })();